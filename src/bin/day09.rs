@@ -1,48 +1,166 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, fmt::Display, str::FromStr};
 
 const PUZZLE: &str = include_str!("../../puzzles/day09.txt");
 
 fn main() {
-    let solver = Puzzle::new(PUZZLE);
-    println!("Part 1: {}", solver.part1());
-    println!("Part 2: {}", solver.part2());
+    let games = match Puzzle::games(PUZZLE) {
+        Ok(games) => games,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let several = games.len() > 1;
+    for solver in games {
+        if several {
+            println!(
+                "{} players, last marble {}:",
+                solver.players, solver.last_marble
+            );
+        }
+        println!("Part 1: {}", solver.part1());
+        println!("Part 2: {}", solver.part2());
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    NoGames,
+    Expected {
+        line: usize,
+        expected: &'static str,
+        found: Option<String>,
+    },
+    Number {
+        line: usize,
+        token: String,
+    },
+    NoPlayers {
+        line: usize,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::NoGames => write!(f, "no games found in input"),
+            ParseError::Expected {
+                line,
+                expected,
+                found: Some(found),
+            } => write!(f, "line {line}: expected {expected}, found \"{found}\""),
+            ParseError::Expected {
+                line,
+                expected,
+                found: None,
+            } => write!(f, "line {line}: expected {expected}, found end of line"),
+            ParseError::Number { line, token } => {
+                write!(f, "line {line}: \"{token}\" is not a non-negative integer")
+            }
+            ParseError::NoPlayers { line } => write!(f, "line {line}: a game needs players"),
+        }
+    }
 }
 
+#[derive(Debug, PartialEq)]
 struct Puzzle {
     players: usize,
     last_marble: usize,
 }
 
+impl FromStr for Puzzle {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_line(1, s)
+    }
+}
+
 impl Puzzle {
+    #[cfg(test)]
     fn new(input: &str) -> Self {
-        let mut it = input.split_ascii_whitespace();
-        Self {
-            players: it.next().unwrap().parse().unwrap(),
-            last_marble: it.nth_back(1).unwrap().parse().unwrap(),
+        match input.parse() {
+            Ok(puzzle) => puzzle,
+            Err(e) => panic!("{e}"),
         }
     }
 
-    fn part1(&self) -> usize {
-        let mut circle = VecDeque::from([0]);
-        let mut scores = vec![0; self.players];
+    /// Parses every non-blank line of the input as its own game.
+    fn games(input: &str) -> Result<Vec<Self>, ParseError> {
+        let games = input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| Self::parse_line(i + 1, line))
+            .collect::<Result<Vec<_>, _>>()?;
+        if games.is_empty() {
+            return Err(ParseError::NoGames);
+        }
+        Ok(games)
+    }
 
-        // This is basically copied from https://www.reddit.com/r/adventofcode/comments/a4i97s/comment/ebepyc7.
-        // It isn't so different conceptually from what I had, but there is one
-        // very very important distinction: I was calling `VecDeque::insert`
-        // instead of rotating the queue. I had assumed that those insertions
-        // would be constant time. Apparently not!
-        for marble in 1..=self.last_marble {
-            if marble.is_multiple_of(23) {
-                circle.rotate_left(7);
-                let player = marble % self.players;
-                scores[player] += marble + circle.pop_front().unwrap();
-                circle.rotate_right(1);
-            } else {
-                circle.rotate_right(1);
-                circle.push_front(marble);
+    /// Validates the whole sentence "N players; last marble is worth M points",
+    /// not just the two numbers in it. Whitespace between words is free-form
+    /// and a trailing period is allowed.
+    fn parse_line(line: usize, text: &str) -> Result<Self, ParseError> {
+        let text = text.trim();
+        let text = text.strip_suffix('.').unwrap_or(text);
+        let mut tokens = text.split_ascii_whitespace();
+
+        let players = number(line, tokens.next(), "the number of players")?;
+        if players == 0 {
+            return Err(ParseError::NoPlayers { line });
+        }
+
+        let mut word = |expected: &'static str, accepted: &[&str]| match tokens.next() {
+            Some(token) if accepted.contains(&token) => Ok(()),
+            found => Err(ParseError::Expected {
+                line,
+                expected,
+                found: found.map(str::to_string),
+            }),
+        };
+        word("\"players;\"", &["players;", "player;"])?;
+        word("\"last\"", &["last"])?;
+        word("\"marble\"", &["marble"])?;
+        word("\"is\"", &["is"])?;
+        word("\"worth\"", &["worth"])?;
+
+        let last_marble = number(line, tokens.next(), "the value of the last marble")?;
+
+        match tokens.next() {
+            Some("points" | "point") => {}
+            found => {
+                return Err(ParseError::Expected {
+                    line,
+                    expected: "\"points\"",
+                    found: found.map(str::to_string),
+                });
             }
         }
+        if let Some(extra) = tokens.next() {
+            return Err(ParseError::Expected {
+                line,
+                expected: "end of line",
+                found: Some(extra.to_string()),
+            });
+        }
+
+        Ok(Self {
+            players,
+            last_marble,
+        })
+    }
+
+    fn game(&self) -> Game {
+        Game::new(self.players, self.last_marble)
+    }
 
+    fn part1(&self) -> usize {
+        let mut scores = vec![0; self.players];
+        for score in self.game() {
+            scores[score.player] += score.points;
+        }
         scores.into_iter().max().unwrap()
     }
 
@@ -55,10 +173,174 @@ impl Puzzle {
     }
 }
 
+fn number(line: usize, token: Option<&str>, expected: &'static str) -> Result<usize, ParseError> {
+    match token {
+        Some(token) => token.parse().map_err(|_| ParseError::Number {
+            line,
+            token: token.to_string(),
+        }),
+        None => Err(ParseError::Expected {
+            line,
+            expected,
+            found: None,
+        }),
+    }
+}
+
+/// A single scoring event: the marble placed on this turn was a multiple of
+/// 23, so the player keeps it along with the marble seven counter-clockwise.
+#[derive(Debug, PartialEq)]
+struct Score {
+    turn: usize,
+    player: usize,
+    marble: usize,
+    removed: usize,
+    points: usize,
+}
+
+/// The marble game as an iterator over scoring events. Turns that only place
+/// a marble in the circle are played silently; the iterator yields on every
+/// turn that scores, so callers can stop early or track running totals.
+struct Game {
+    circle: VecDeque<usize>,
+    players: usize,
+    marble: usize,
+    last_marble: usize,
+}
+
+impl Game {
+    fn new(players: usize, last_marble: usize) -> Self {
+        Self {
+            circle: VecDeque::from([0]),
+            players,
+            marble: 0,
+            last_marble,
+        }
+    }
+}
+
+impl Iterator for Game {
+    type Item = Score;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // This is basically copied from https://www.reddit.com/r/adventofcode/comments/a4i97s/comment/ebepyc7.
+        // It isn't so different conceptually from what I had, but there is one
+        // very very important distinction: I was calling `VecDeque::insert`
+        // instead of rotating the queue. I had assumed that those insertions
+        // would be constant time. Apparently not!
+        while self.marble < self.last_marble {
+            self.marble += 1;
+            let marble = self.marble;
+            if marble.is_multiple_of(23) {
+                self.circle.rotate_left(7);
+                let removed = self.circle.pop_front().unwrap();
+                self.circle.rotate_right(1);
+                return Some(Score {
+                    turn: marble,
+                    player: marble % self.players,
+                    marble,
+                    removed,
+                    points: marble + removed,
+                });
+            } else {
+                self.circle.rotate_right(1);
+                self.circle.push_front(marble);
+            }
+        }
+        None
+    }
+}
+
 #[cfg(test)]
 mod marble_mania {
     use super::*;
 
+    #[test]
+    fn scoring_events() {
+        let mut game = Puzzle::new("9 players; last marble is worth 25 points").game();
+        assert_eq!(
+            game.next(),
+            Some(Score {
+                turn: 23,
+                player: 5,
+                marble: 23,
+                removed: 9,
+                points: 32,
+            })
+        );
+        assert_eq!(game.next(), None)
+    }
+
+    #[test]
+    fn running_leader() {
+        let puzzle = Puzzle::new("10 players; last marble is worth 1618 points");
+        let mut scores = vec![0; puzzle.players];
+        let mut leader = 0;
+        for score in puzzle.game() {
+            scores[score.player] += score.points;
+            leader = leader.max(scores[score.player]);
+        }
+        assert_eq!(leader, puzzle.part1())
+    }
+
+    #[test]
+    fn messy_sentence() {
+        assert_eq!(
+            Puzzle::new("  9   players;\tlast marble is worth  25 points.\n"),
+            Puzzle {
+                players: 9,
+                last_marble: 25
+            }
+        )
+    }
+
+    #[test]
+    fn many_games() {
+        let games = Puzzle::games(
+            "9 players; last marble is worth 25 points
+
+10 players; last marble is worth 1618 points
+",
+        )
+        .unwrap();
+        assert_eq!(
+            games.iter().map(Puzzle::part1).collect::<Vec<_>>(),
+            vec![32, 8317]
+        )
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Puzzle::games(" \n\n"), Err(ParseError::NoGames));
+        assert_eq!(
+            Puzzle::games("9 players; last marble is worth 25 points\nnine players"),
+            Err(ParseError::Number {
+                line: 2,
+                token: "nine".to_string()
+            })
+        );
+        assert_eq!(
+            "9 players; last marble is worth 25".parse::<Puzzle>(),
+            Err(ParseError::Expected {
+                line: 1,
+                expected: "\"points\"",
+                found: None
+            })
+        );
+        assert_eq!(
+            "9 players; first marble is worth 25 points".parse::<Puzzle>(),
+            Err(ParseError::Expected {
+                line: 1,
+                expected: "\"last\"",
+                found: Some("first".to_string())
+            })
+        );
+        assert_eq!(
+            "0 players; last marble is worth 25 points".parse::<Puzzle>(),
+            Err(ParseError::NoPlayers { line: 1 })
+        );
+    }
+
     #[test]
     fn test9_25() {
         assert_eq!(
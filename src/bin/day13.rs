@@ -1,6 +1,15 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::BufRead,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
 use advent_of_code_2018::Solver;
+use ndarray::Array2;
 use num_complex::Complex;
 
 const PUZZLE: &str = include_str!("../../puzzles/day13.txt");
@@ -8,18 +17,236 @@ const PUZZLE: &str = include_str!("../../puzzles/day13.txt");
 /// Man, I really thought this one was going to take some crazy modulo
 /// arithmetic. Turns out you have to read the instructions carefully.
 /// No tricks, just a tricky procedural puzzle.
+///
+/// Run with `--animate` to watch the carts crash into each other, with
+/// `--delay MS` between frames (100 by default). `--step` starts paused.
+/// `--events` prints everything that happens in part 2 as JSON lines.
+/// `--policy stop|remove|bounce|pass` runs with another collision policy, and
+/// `--decisions LSRU` changes what carts do at intersections (U turns around).
+/// `--svg FILE` draws the carts' paths through part 2 to an SVG file.
+/// `--fast` moves each cart straight to the end of its run instead of one
+/// tick at a time. That pays off most on long straights: `bench_part2` goes
+/// from 0.37 s to 0.11 s, and the twisty `bench_random_map` from 4.4 s to
+/// 2.5 s.
+/// `--generate SEED WIDTH HEIGHT LOOPS CARTS [--packed]` prints a random map
+/// instead, and `--map FILE` runs on some other map than the puzzle input.
 fn main() {
-    let first_collisions = Puzzle::new(PUZZLE).part1();
-    println!(
-        "Part 1: {},{}",
-        first_collisions[0].re, first_collisions[0].im
-    );
-    let mut puzzle = Puzzle::new(PUZZLE);
-    let last_cart = puzzle.part2();
-    println!("Part 2: {},{}", last_cart.re, last_cart.im);
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--generate") {
+        let number = |j: usize| -> usize {
+            args.get(i + j)
+                .and_then(|n| n.parse().ok())
+                .expect("--generate needs SEED WIDTH HEIGHT LOOPS CARTS")
+        };
+        let layout = if args.iter().any(|arg| arg == "--packed") {
+            Layout::Packed
+        } else {
+            Layout::Spaced
+        };
+        let (width, height, loops, carts) = (number(2), number(3), number(4), number(5));
+        print!(
+            "{}",
+            random_map(number(1) as u64, width, height, loops, carts, layout)
+        );
+        return;
+    }
+    let input = match args.iter().position(|arg| arg == "--map") {
+        Some(i) => std::fs::read_to_string(&args[i + 1]).expect("could not read the map"),
+        None => PUZZLE.to_string(),
+    };
+    if let Err(defects) = Puzzle::new(&input).validate() {
+        for defect in defects {
+            println!("{defect}");
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--events") {
+        let mut puzzle = Puzzle::new(&input).with_dense_tracks().with_event_log();
+        let result = puzzle.part2();
+        for event in puzzle.events.unwrap_or_default() {
+            println!("{}", event.to_json());
+        }
+        if let Err(e) = result {
+            eprintln!("{e}");
+        }
+        return;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--svg") {
+        let path = args.get(i + 1).expect("--svg needs a file name");
+        let mut puzzle = Puzzle::new(&input).with_dense_tracks().with_event_log();
+        if let Err(e) = puzzle.part2() {
+            println!("{e}");
+        }
+        if let Err(e) = std::fs::write(path, puzzle.svg().to_string()) {
+            println!("could not write {path}: {e}");
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--animate") {
+        let delay = args
+            .iter()
+            .position(|arg| arg == "--delay")
+            .and_then(|i| args.get(i + 1))
+            .map_or(100, |ms| ms.parse().expect("delay must be milliseconds"));
+        let paused = args.iter().any(|arg| arg == "--step");
+        let puzzle = Puzzle::new(&input).with_dense_tracks();
+        if let Err(e) = animate(puzzle, Duration::from_millis(delay), paused) {
+            println!("{e}");
+        }
+        return;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--policy") {
+        let policy = CollisionPolicy::from(&args[i + 1]);
+        let mut puzzle = Puzzle::new(&input).with_dense_tracks().with_policy(policy);
+        if let Some(i) = args.iter().position(|arg| arg == "--decisions") {
+            puzzle = puzzle.with_decisions(args[i + 1].chars().map(Decision::from).collect());
+        }
+        match puzzle.run() {
+            Ok(outcome) => print!("{outcome}"),
+            Err(e) => println!("{e}"),
+        }
+        return;
+    }
+    let fast = args.iter().any(|arg| arg == "--fast");
+    let puzzle = || {
+        let puzzle = Puzzle::new(&input).with_dense_tracks();
+        if fast {
+            puzzle.with_fast_forward()
+        } else {
+            puzzle
+        }
+    };
+    match puzzle().with_policy(CollisionPolicy::StopAtFirst).run() {
+        Ok(Outcome::Crashed(crashes)) => println!(
+            "Part 1: {},{} (cart {} hit cart {})",
+            crashes[0].position.re,
+            crashes[0].position.im,
+            crashes[0].carts[0],
+            crashes[0].carts[1]
+        ),
+        Ok(outcome) => print!("Part 1: {outcome}"),
+        Err(e) => println!("Part 1: {e}"),
+    }
+    let mut puzzle = puzzle();
+    match puzzle.part2() {
+        Ok(last_cart) => println!(
+            "Part 2: {},{} (cart {})",
+            last_cart.re, last_cart.im, puzzle.carts[0].id
+        ),
+        Err(e) => println!("Part 2: {e}"),
+    }
     println!("{puzzle}");
 }
 
+/// Redraw the map after every tick until one cart is left. Press Enter to
+/// pause; while paused, Enter advances one tick and `c` continues. `q` quits.
+fn animate(mut puzzle: Puzzle, delay: Duration, mut paused: bool) -> Result<(), SimulationError> {
+    // Reading stdin blocks, so it gets a thread of its own.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            if tx.send(line.unwrap_or_default()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let draw = |puzzle: &Puzzle, paused: bool| {
+        print!("\x1b[2J\x1b[H{puzzle}");
+        println!("tick {}, {} carts", puzzle.tick, puzzle.carts.len());
+        if paused {
+            println!("paused: Enter to step, c to continue, q to quit");
+        } else {
+            println!("Enter to pause, q to quit");
+        }
+    };
+
+    draw(&puzzle, paused);
+    while puzzle.carts.len() > 1 {
+        let command = if paused {
+            // With nothing left to read from stdin, there is nobody to press
+            // Enter, so just play the rest.
+            rx.recv().inspect_err(|_| paused = false).ok()
+        } else {
+            thread::sleep(delay);
+            rx.try_recv().ok()
+        };
+        match command.as_deref().map(str::trim) {
+            Some("q") => return Ok(()),
+            Some("c") => paused = false,
+            Some(_) if !paused => {
+                paused = true;
+                draw(&puzzle, paused);
+                continue;
+            }
+            _ => {}
+        }
+
+        let crashes = puzzle.tick_once()?;
+        puzzle.remove_crashed(&crashes);
+        draw(&puzzle, paused);
+    }
+    Ok(())
+}
+
+/// Things that keep a simulation from giving an answer. The tick is counted
+/// from 1, and the cart is numbered in reading order of the original map.
+#[derive(Debug, PartialEq)]
+enum SimulationError {
+    /// The carts got back into a configuration they were already in at tick
+    /// `since` without crashing (part 1) or getting down to one cart (part 2).
+    NeverResolves { since: usize, period: usize },
+    /// The last carts all crashed on this tick, so there is no last cart.
+    NoCartsLeft { tick: usize },
+    /// The cart moved onto a cell with no track.
+    OffTrack {
+        cart: usize,
+        tick: usize,
+        position: Complex<isize>,
+    },
+    /// The cart moved onto a straight piece from the side.
+    WrongAxis {
+        cart: usize,
+        tick: usize,
+        position: Complex<isize>,
+        velocity: Complex<isize>,
+    },
+}
+
+impl Display for SimulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimulationError::NeverResolves { since, period } => write!(
+                f,
+                "never resolves: the carts repeat every {period} ticks from tick {since}"
+            ),
+            SimulationError::NoCartsLeft { tick } => {
+                write!(f, "no carts are left after tick {tick}")
+            }
+            SimulationError::OffTrack {
+                cart,
+                tick,
+                position,
+            } => write!(
+                f,
+                "cart {cart} left the rails at ({},{}) on tick {tick}",
+                position.re, position.im
+            ),
+            SimulationError::WrongAxis {
+                cart,
+                tick,
+                position,
+                velocity,
+            } => write!(
+                f,
+                "cart {cart} hit the side of the track at ({},{}) moving ({},{}) on tick {tick}",
+                position.re, position.im, velocity.re, velocity.im
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Track {
     Vertical,
     Horizontal,
@@ -28,6 +255,44 @@ enum Track {
     Intersection,
 }
 
+const NORTH: Complex<isize> = Complex::new(0, -1);
+const SOUTH: Complex<isize> = Complex::new(0, 1);
+const EAST: Complex<isize> = Complex::new(1, 0);
+const WEST: Complex<isize> = Complex::new(-1, 0);
+
+impl Track {
+    /// The track under a cell of the map, not counting carts.
+    fn from(c: char) -> Option<Self> {
+        match c {
+            '|' => Some(Track::Vertical),
+            '-' => Some(Track::Horizontal),
+            '/' => Some(Track::TurnSE),
+            '\\' => Some(Track::TurnSW),
+            '+' => Some(Track::Intersection),
+            _ => None,
+        }
+    }
+
+    /// The ways a piece of track can be connected to its neighbours. A corner
+    /// could be either of two corners of a loop; which one depends on where
+    /// the rest of the track is.
+    fn orientations(&self) -> &'static [&'static [Complex<isize>]] {
+        match self {
+            Track::Vertical => &[&[NORTH, SOUTH]],
+            Track::Horizontal => &[&[EAST, WEST]],
+            Track::TurnSE => &[&[SOUTH, EAST], &[NORTH, WEST]],
+            Track::TurnSW => &[&[NORTH, EAST], &[SOUTH, WEST]],
+            Track::Intersection => &[&[NORTH, SOUTH, EAST, WEST]],
+        }
+    }
+
+    fn can_connect(&self, direction: Complex<isize>) -> bool {
+        self.orientations()
+            .iter()
+            .any(|orientation| orientation.contains(&direction))
+    }
+}
+
 impl Display for Track {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -45,44 +310,648 @@ impl Display for Track {
     }
 }
 
-#[derive(Debug)]
+/// Where the track pieces are. The puzzle input is mostly empty space, so
+/// I started with a map of only the cells that have track, but every cart
+/// move has to hash its new position to find out what it landed on. The
+/// dense grid spends a byte or two on every empty cell and gets a plain
+/// array lookup in return. On the 200,000 column racetrack in `bench_part2`,
+/// part 2 takes about 0.65 s with the map and 0.37 s with the grid (release
+/// build).
+enum Tracks {
+    Sparse(HashMap<Complex<isize>, Track>),
+    Dense(Array2<Option<Track>>),
+}
+
+impl Tracks {
+    fn get(&self, p: &Complex<isize>) -> Option<&Track> {
+        match self {
+            Tracks::Sparse(tracks) => tracks.get(p),
+            Tracks::Dense(grid) => {
+                let (Ok(x), Ok(y)) = (usize::try_from(p.re), usize::try_from(p.im)) else {
+                    return None;
+                };
+                grid.get((y, x)).and_then(Option::as_ref)
+            }
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Complex<isize>, &Track)> + '_> {
+        match self {
+            Tracks::Sparse(tracks) => Box::new(tracks.iter().map(|(&p, t)| (p, t))),
+            Tracks::Dense(grid) => Box::new(grid.indexed_iter().filter_map(|((y, x), t)| {
+                t.as_ref()
+                    .map(|t| (Complex::new(x as isize, y as isize), t))
+            })),
+        }
+    }
+
+    /// Does the track at `p` lead into a neighbour that leads back?
+    fn connects(&self, p: Complex<isize>, direction: Complex<isize>) -> bool {
+        self.get(&(p + direction))
+            .is_some_and(|neighbour| neighbour.can_connect(-direction))
+    }
+
+    /// Does the track at `p` lead into a neighbour that actually leads back?
+    /// Unlike `connects`, a corner only counts if it bends back this way.
+    fn joins(&self, p: Complex<isize>, direction: Complex<isize>) -> bool {
+        let q = p + direction;
+        self.get(&q).is_some_and(|neighbour| match neighbour {
+            Track::TurnSE | Track::TurnSW => self.orientation(q, neighbour).contains(&-direction),
+            _ => neighbour.can_connect(-direction),
+        })
+    }
+
+    /// Which way a piece of track actually runs. For a corner, that's the
+    /// orientation its neighbours agree with (or the first, on a broken map).
+    fn orientation(&self, p: Complex<isize>, track: &Track) -> &'static [Complex<isize>] {
+        let orientations = track.orientations();
+        orientations
+            .iter()
+            .find(|o| o.iter().all(|&d| self.connects(p, d)))
+            .unwrap_or(&orientations[0])
+    }
+
+    fn to_dense(&self) -> Self {
+        let rows = self.iter().map(|(p, _)| p.im + 1).max().unwrap_or(0);
+        let cols = self.iter().map(|(p, _)| p.re + 1).max().unwrap_or(0);
+        let mut grid = Array2::from_elem((rows as usize, cols as usize), None);
+        for (p, &t) in self.iter() {
+            grid[(p.im as usize, p.re as usize)] = Some(t);
+        }
+        Tracks::Dense(grid)
+    }
+}
+
+/// How many straight pieces lie ahead of each piece of track, in each
+/// direction, before the next corner or intersection (or the end of the
+/// line). A cart can cover that much ground without anything changing but
+/// where it is. This is a grid like the dense tracks, at eight bytes a cell,
+/// so runs longer than a `u16` just get cut short; the carts will take a
+/// few hops to get to the end of those instead of one.
+struct Runs(Array2<[u16; 4]>);
+
+impl Runs {
+    const DIRECTIONS: [Complex<isize>; 4] = [NORTH, SOUTH, EAST, WEST];
+
+    fn new(tracks: &Tracks) -> Self {
+        let rows = tracks.iter().map(|(p, _)| p.im + 1).max().unwrap_or(0) as usize;
+        let cols = tracks.iter().map(|(p, _)| p.re + 1).max().unwrap_or(0) as usize;
+        let straight = |y: usize, x: usize, d: Complex<isize>| {
+            let track = if d.im == 0 {
+                Track::Horizontal
+            } else {
+                Track::Vertical
+            };
+            tracks.get(&Complex::new(x as isize, y as isize)) == Some(&track)
+        };
+        let mut runs = Array2::from_elem((rows, cols), [0u16; 4]);
+        for (i, d) in Self::DIRECTIONS.into_iter().enumerate() {
+            // Work backwards from the far end, so the run from the next piece
+            // along is always ready by the time we need it.
+            let ys: Vec<usize> = if d == SOUTH {
+                (0..rows).rev().collect()
+            } else {
+                (0..rows).collect()
+            };
+            let xs: Vec<usize> = if d == EAST {
+                (0..cols).rev().collect()
+            } else {
+                (0..cols).collect()
+            };
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    let (Some(ny), Some(nx)) = (
+                        y.checked_add_signed(d.im).filter(|&ny| ny < rows),
+                        x.checked_add_signed(d.re).filter(|&nx| nx < cols),
+                    ) else {
+                        continue;
+                    };
+                    if straight(ny, nx, d) {
+                        runs[(y, x)][i] = runs[(ny, nx)][i].saturating_add(1);
+                    }
+                }
+            }
+        }
+        Runs(runs)
+    }
+
+    fn get(&self, p: Complex<isize>, d: Complex<isize>) -> usize {
+        let i = Self::DIRECTIONS.iter().position(|&e| e == d).unwrap();
+        let (Ok(x), Ok(y)) = (usize::try_from(p.re), usize::try_from(p.im)) else {
+            return 0;
+        };
+        self.0.get((y, x)).map_or(0, |runs| runs[i] as usize)
+    }
+}
+
+/// A cart's straight run when fast-forwarding. Its position only gets
+/// brought up to date when something is about to happen to it, so until
+/// then it's wherever it was on tick `at`, and `velocity * (t - at)` further
+/// along on tick `t`.
+#[derive(Debug, Clone, Copy)]
+struct Leg {
+    at: usize,
+    /// The tick it gets to the end of the straight: a corner, an
+    /// intersection, or wherever the track gives out.
+    end: usize,
+    /// The next tick it has to be moved by hand: `end`, or the very next
+    /// tick if another cart might get in its way before then.
+    wake: usize,
+    /// The row (`true`) or column (`false`) it runs along.
+    line: (bool, isize),
+    /// Which of `Runs::DIRECTIONS` it's heading in.
+    heading: usize,
+    /// Its share of `Schedule::sums`.
+    key: u64,
+}
+
+/// Fast-forwarding moves every cart straight to the end of its run in one
+/// go, and only moves carts a tick at a time while they might run into each
+/// other. Most ticks, nobody needs moving at all.
+///
+/// Watching for cycles still needs the state on every tick, and working
+/// that out from scratch would mean moving every cart every tick after all.
+/// Instead, the state hash is a sum over the carts of `x^a y^b` times a
+/// hash of everything else about the cart, for a cart at `(a, b)`. A cart
+/// heading along `v` is at `p - v t` on tick `t`, so its term is its
+/// share of `sums[v]` times the same `powers[v]` for every cart heading
+/// that way, and the whole sum only changes when some cart starts a new run.
+#[derive(Clone)]
+struct Schedule {
+    /// By cart id, until the cart is taken off the map.
+    legs: Vec<Option<Leg>>,
+    /// Where each cart is in `Puzzle::carts`, by id.
+    slots: Vec<usize>,
+    /// Which carts wake on each tick. Waking a cart early leaves its old
+    /// entry behind, so check entries against `Leg::wake`.
+    queue: BTreeMap<usize, Vec<usize>>,
+    /// The carts running along each row or column.
+    lines: HashMap<(bool, isize), Vec<usize>>,
+    /// The carts that set off on a new run on the latest tick.
+    moved: Vec<usize>,
+    sums: [u64; 4],
+    /// The weight of each of `Runs::DIRECTIONS`, to the power of the tick.
+    powers: [u64; 4],
+}
+
+/// The state hash while fast-forwarding works modulo this prime, 2^61 - 1.
+const MODULUS: u64 = (1 << 61) - 1;
+const X: u64 = 0x0a3f_5c71_9e2b_4d68;
+const Y: u64 = 0x1b87_3e06_52c9_f1a3;
+const X_INVERSE: u64 = pow_mod(X, MODULUS - 2);
+const Y_INVERSE: u64 = pow_mod(Y, MODULUS - 2);
+
+const fn mul_mod(a: u64, b: u64) -> u64 {
+    let product = a as u128 * b as u128;
+    ((product as u64 & MODULUS) + (product >> 61) as u64) % MODULUS
+}
+
+const fn pow_mod(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base);
+        }
+        base = mul_mod(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// `x^a y^b` for a cell at `(a, b)`, which can be anywhere at all.
+fn weight(p: Complex<isize>) -> u64 {
+    let power = |base: u64, inverse: u64, e: isize| {
+        pow_mod(if e < 0 { inverse } else { base }, e.unsigned_abs() as u64)
+    };
+    mul_mod(power(X, X_INVERSE, p.re), power(Y, Y_INVERSE, p.im))
+}
+
+/// Everything about a cart except where it is: where it's going, which way
+/// it came in, and what it'll do at the next intersection.
+fn cart_key(velocity: Complex<isize>, behind: Complex<isize>, decision: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (velocity.re, velocity.im, behind.re, behind.im, decision).hash(&mut hasher);
+    hasher.finish() % MODULUS
+}
+
+/// Could two carts heading straight along their runs, `a.2` and `b.2` ticks
+/// more, ever be in the same cell on the same tick, or one tick apart? That
+/// has to happen for either of them to move into the other.
+fn meets(
+    a: (Complex<isize>, Complex<isize>, usize),
+    b: (Complex<isize>, Complex<isize>, usize),
+) -> bool {
+    let ((pa, va, la), (pb, vb, lb)) = (a, b);
+    let dot = |p: Complex<isize>, v: Complex<isize>| p.re * v.re + p.im * v.im;
+    // `a` after `i` ticks is where `b` is after `j` ticks.
+    let fits = |i: isize, j: isize| {
+        (0..=la as isize).contains(&i) && (0..=lb as isize).contains(&j) && (i - j).abs() <= 1
+    };
+    let d = pb - pa;
+    if va == vb {
+        // Following each other, always the same distance apart.
+        let k = dot(d, va);
+        d == va * k && fits(k.max(0), (-k).max(0))
+    } else if va == -vb {
+        // Heading for each other, meeting in the middle.
+        let m = dot(d, va);
+        d == va * m && m >= 0 && (fits(m / 2, m - m / 2) || fits(m - m / 2, m / 2))
+    } else {
+        // Crossing, where the row of one meets the column of the other.
+        let c = if va.im == 0 {
+            Complex::new(pb.re, pa.im)
+        } else {
+            Complex::new(pa.re, pb.im)
+        };
+        fits(dot(c - pa, va), dot(c - pb, vb))
+    }
+}
+
+impl Schedule {
+    fn new(runs: &Runs, carts: &[Cart], tick: usize) -> Self {
+        let ids = carts.iter().map(|cart| cart.id + 1).max().unwrap_or(0);
+        let mut slots = vec![usize::MAX; ids];
+        for (i, cart) in carts.iter().enumerate() {
+            slots[cart.id] = i;
+        }
+        let mut schedule = Self {
+            legs: vec![None; ids],
+            slots,
+            queue: BTreeMap::new(),
+            lines: HashMap::new(),
+            moved: vec![],
+            sums: [0; 4],
+            powers: Runs::DIRECTIONS.map(|d| weight(d * tick as isize)),
+        };
+        for cart in carts.iter() {
+            schedule.start(runs, carts, cart.id, tick);
+            schedule.moved.push(cart.id);
+        }
+        schedule
+    }
+
+    /// Send a cart off down the track from where it is now. If it might meet
+    /// anyone on the way, they both get moved by hand from the next tick on.
+    fn start(&mut self, runs: &Runs, carts: &[Cart], id: usize, now: usize) {
+        let cart = &carts[self.slots[id]];
+        let (p, v) = (cart.position, cart.velocity);
+        let end = now + runs.get(p, v) + 1;
+        let far = p + v * (end - now) as isize;
+        let line = if v.im == 0 {
+            (true, p.im)
+        } else {
+            (false, p.re)
+        };
+        // Runs at right angles can only meet where one of them starts or
+        // ends, since everything in between is straight track.
+        let across = |q: Complex<isize>| {
+            if v.im == 0 {
+                (false, q.re)
+            } else {
+                (true, q.im)
+            }
+        };
+        let mut others: Vec<usize> = [line, across(p), across(far)]
+            .iter()
+            .filter_map(|line| self.lines.get(line))
+            .flatten()
+            .copied()
+            .collect();
+        others.sort_unstable();
+        others.dedup();
+
+        let mut wake = end;
+        for other in others {
+            let leg = self.legs[other].expect("carts on a line have a leg");
+            let them = &carts[self.slots[other]];
+            let q = them.position + them.velocity * (now - leg.at) as isize;
+            if meets((p, v, end - now), (q, them.velocity, leg.end - now)) {
+                wake = now + 1;
+                self.wake(other, now + 1);
+            }
+        }
+
+        let heading = Runs::DIRECTIONS.iter().position(|&d| d == v).unwrap();
+        let key = mul_mod(
+            weight(p - v * now as isize),
+            cart_key(v, -v, cart.next_decision),
+        );
+        self.sums[heading] = (self.sums[heading] + key) % MODULUS;
+        self.lines.entry(line).or_default().push(id);
+        self.queue.entry(wake).or_default().push(id);
+        self.legs[id] = Some(Leg {
+            at: now,
+            end,
+            wake,
+            line,
+            heading,
+            key,
+        });
+    }
+
+    fn wake(&mut self, id: usize, tick: usize) {
+        if let Some(leg) = self.legs[id].as_mut()
+            && leg.wake > tick
+        {
+            leg.wake = tick;
+            self.queue.entry(tick).or_default().push(id);
+        }
+    }
+
+    /// Forget a cart's run, because it's about to start another one or
+    /// leave the map.
+    fn stop(&mut self, id: usize) {
+        if let Some(leg) = self.legs[id].take() {
+            if let Some(ids) = self.lines.get_mut(&leg.line) {
+                ids.retain(|&other| other != id);
+            }
+            self.sums[leg.heading] = (self.sums[leg.heading] + MODULUS - leg.key) % MODULUS;
+        }
+    }
+
+    /// A hash of `Puzzle::state` for the current tick. Carts that have just
+    /// set off might have come in from another direction, so they get
+    /// corrected; and carts sharing a cell, which can only be ones that just
+    /// moved, get a term for the order they're in, since that decides who
+    /// moves first.
+    fn hash(&self, carts: &[Cart]) -> u64 {
+        let mut hash = (0..4).fold(0, |hash, i| {
+            (hash + mul_mod(self.sums[i], self.powers[i])) % MODULUS
+        });
+        let mut moved: Vec<&Cart> = self
+            .moved
+            .iter()
+            .map(|&id| &carts[self.slots[id]])
+            .collect();
+        moved.sort_by_key(|cart| (cart.position.im, cart.position.re));
+        for cart in moved.iter() {
+            let v = cart.velocity;
+            let exact = cart_key(v, cart.previous - cart.position, cart.next_decision);
+            let assumed = cart_key(v, -v, cart.next_decision);
+            let correction = mul_mod(weight(cart.position), (exact + MODULUS - assumed) % MODULUS);
+            hash = (hash + correction) % MODULUS;
+        }
+        for group in moved.chunk_by(|a, b| a.position == b.position) {
+            if group.len() > 1 {
+                let mut hasher = DefaultHasher::new();
+                for cart in group {
+                    (cart.position.re, cart.position.im).hash(&mut hasher);
+                    (cart.velocity.re, cart.velocity.im).hash(&mut hasher);
+                    (cart.previous.re, cart.previous.im).hash(&mut hasher);
+                    cart.next_decision.hash(&mut hasher);
+                }
+                hash = (hash + hasher.finish() % MODULUS) % MODULUS;
+            }
+        }
+        hash
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Decision {
     Left,
     Straight,
     Right,
+    /// Not in the puzzle: go back the way you came.
+    Reverse,
+}
+
+impl Decision {
+    fn from(c: char) -> Self {
+        match c {
+            'L' => Self::Left,
+            'S' => Self::Straight,
+            'R' => Self::Right,
+            'U' => Self::Reverse,
+            _ => panic!("unexpected decision {c}"),
+        }
+    }
+}
+
+/// What happens when a cart moves into another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CollisionPolicy {
+    /// The simulation ends on the first tick with a crash (part 1).
+    StopAtFirst,
+    /// Both carts come off the track at the end of the tick (part 2).
+    RemoveBoth,
+    /// Both carts turn around and carry on.
+    Bounce,
+    /// Nothing happens; the carts carry on through each other.
+    PassThrough,
+}
+
+impl CollisionPolicy {
+    fn from(name: &str) -> Self {
+        match name {
+            "stop" => Self::StopAtFirst,
+            "remove" => Self::RemoveBoth,
+            "bounce" => Self::Bounce,
+            "pass" => Self::PassThrough,
+            _ => panic!("unexpected collision policy {name}"),
+        }
+    }
+}
+
+/// How a `Puzzle::run` ended.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The crashes on the first tick that had any.
+    Crashed(Vec<Crash>),
+    /// At most one cart is left; this is its id, if there is one.
+    Resolved(Option<usize>),
+    /// The state after tick `since + period` was the same as after tick
+    /// `since`, so nothing new is ever going to happen.
+    Cycle { since: usize, period: usize },
 }
 
-#[derive(Debug)]
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Crashed(crashes) => {
+                for crash in crashes {
+                    writeln!(
+                        f,
+                        "cart {} hit cart {} at {},{}",
+                        crash.carts[0], crash.carts[1], crash.position.re, crash.position.im
+                    )?;
+                }
+                Ok(())
+            }
+            Outcome::Resolved(Some(cart)) => writeln!(f, "cart {cart} is the last one left"),
+            Outcome::Resolved(None) => writeln!(f, "no carts are left"),
+            Outcome::Cycle { since, period } => {
+                writeln!(f, "never resolves, period {period} (from tick {since})")
+            }
+        }
+    }
+}
+
+/// Something that happened to a cart. Every cart move is logged, along with
+/// anything the cart did on the piece of track it arrived at.
+#[derive(Debug, PartialEq)]
+enum Event {
+    /// When fast-forwarding, one move can cover a whole straight run; `tick`
+    /// is the one it finished on. Those get logged when the cart next has to
+    /// move by hand, so the log is in order for each cart, but not overall.
+    Moved {
+        tick: usize,
+        cart: usize,
+        from: Complex<isize>,
+        to: Complex<isize>,
+    },
+    /// Followed a corner.
+    Turned {
+        tick: usize,
+        cart: usize,
+        position: Complex<isize>,
+        track: Track,
+    },
+    /// Chose a way through an intersection.
+    Decided {
+        tick: usize,
+        cart: usize,
+        position: Complex<isize>,
+        decision: Decision,
+    },
+    /// `carts[0]` moved into `carts[1]`.
+    Collided {
+        tick: usize,
+        carts: [usize; 2],
+        position: Complex<isize>,
+    },
+    Removed {
+        tick: usize,
+        cart: usize,
+        position: Complex<isize>,
+    },
+}
+
+impl Event {
+    /// One line of JSON, with positions as `[x, y]` pairs.
+    fn to_json(&self) -> String {
+        let xy = |p: &Complex<isize>| format!("[{},{}]", p.re, p.im);
+        match self {
+            Event::Moved {
+                tick,
+                cart,
+                from,
+                to,
+            } => format!(
+                r#"{{"tick":{tick},"event":"moved","cart":{cart},"from":{},"to":{}}}"#,
+                xy(from),
+                xy(to)
+            ),
+            Event::Turned {
+                tick,
+                cart,
+                position,
+                track,
+            } => format!(
+                r#"{{"tick":{tick},"event":"turned","cart":{cart},"position":{},"track":"{}"}}"#,
+                xy(position),
+                track.to_string().replace('\\', "\\\\")
+            ),
+            Event::Decided {
+                tick,
+                cart,
+                position,
+                decision,
+            } => format!(
+                r#"{{"tick":{tick},"event":"decided","cart":{cart},"position":{},"decision":"{}"}}"#,
+                xy(position),
+                format!("{decision:?}").to_lowercase()
+            ),
+            Event::Collided {
+                tick,
+                carts,
+                position,
+            } => format!(
+                r#"{{"tick":{tick},"event":"collided","carts":[{},{}],"position":{}}}"#,
+                carts[0],
+                carts[1],
+                xy(position)
+            ),
+            Event::Removed {
+                tick,
+                cart,
+                position,
+            } => format!(
+                r#"{{"tick":{tick},"event":"removed","cart":{cart},"position":{}}}"#,
+                xy(position)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Cart {
+    id: usize,
     position: Complex<isize>,
     velocity: Complex<isize>,
-    next_decision: Decision,
+    /// Where the cart was before its last move.
+    previous: Complex<isize>,
+    /// An index into the puzzle's cycle of decisions.
+    next_decision: usize,
 }
 
 impl Cart {
-    fn new(position: Complex<isize>, velocity: Complex<isize>) -> Self {
+    fn new(id: usize, position: Complex<isize>, velocity: Complex<isize>) -> Self {
         Self {
+            id,
             position,
             velocity,
-            next_decision: Decision::Left,
+            previous: position - velocity,
+            next_decision: 0,
         }
     }
 
-    fn tick(&mut self, tracks: &HashMap<Complex<isize>, Track>) {
+    fn state(&self) -> CartState {
+        (
+            self.position,
+            self.velocity,
+            self.previous,
+            self.next_decision,
+        )
+    }
+
+    /// Head back out the way we came in. On a corner or an intersection,
+    /// that isn't just the opposite of the way we're pointing now.
+    fn turn_around(&mut self) {
+        self.velocity = self.previous - self.position;
+    }
+
+    fn tick(
+        &mut self,
+        tracks: &Tracks,
+        decisions: &[Decision],
+        tick: usize,
+    ) -> Result<(), SimulationError> {
+        self.previous = self.position;
         self.position += self.velocity;
+        let wrong_axis = || SimulationError::WrongAxis {
+            cart: self.id,
+            tick,
+            position: self.position,
+            velocity: self.velocity,
+        };
         match tracks.get(&self.position) {
             None => {
-                let msg = format!(
-                    "not on the rails: ({},{})",
-                    self.position.re, self.position.im
-                );
-                panic!("{}", msg)
+                return Err(SimulationError::OffTrack {
+                    cart: self.id,
+                    tick,
+                    position: self.position,
+                });
             }
             Some(Track::Vertical) => {
-                assert!(self.velocity.re == 0 && self.velocity.im.abs() == 1)
+                if !(self.velocity.re == 0 && self.velocity.im.abs() == 1) {
+                    return Err(wrong_axis());
+                }
             }
             Some(Track::Horizontal) => {
-                assert!(self.velocity.re.abs() == 1 && self.velocity.im == 0)
+                if !(self.velocity.re.abs() == 1 && self.velocity.im == 0) {
+                    return Err(wrong_axis());
+                }
             }
             Some(Track::TurnSE) => {
                 // Thanks for the tip, @Zefick.
@@ -95,30 +964,577 @@ impl Cart {
                 self.velocity = Complex::new(self.velocity.im, self.velocity.re);
             }
             Some(Track::Intersection) => {
-                match self.next_decision {
+                match decisions[self.next_decision] {
                     Decision::Left => self.velocity *= -Complex::i(),
                     Decision::Straight => {}
                     Decision::Right => self.velocity *= Complex::i(),
+                    Decision::Reverse => self.velocity = -self.velocity,
                 };
-                self.next_decision = match self.next_decision {
-                    Decision::Left => Decision::Straight,
-                    Decision::Straight => Decision::Right,
-                    Decision::Right => Decision::Left,
-                }
+                self.next_decision = (self.next_decision + 1) % decisions.len();
             }
         }
+        Ok(())
+    }
+}
+
+/// Cart `carts[0]` moved into cart `carts[1]`. Carts are numbered in reading
+/// order of the original map, and they keep their numbers no matter how
+/// often they are sorted or removed.
+#[derive(Debug, Clone, PartialEq)]
+struct Crash {
+    position: Complex<isize>,
+    carts: [usize; 2],
+}
+
+/// Something wrong with a map that we can see before running it.
+#[derive(Debug, PartialEq)]
+enum Defect {
+    /// A straight piece or an intersection leads nowhere in this direction.
+    Dangling {
+        position: Complex<isize>,
+        direction: Complex<isize>,
+    },
+    /// A corner should join up with its neighbours in exactly one of its two
+    /// orientations. This is how many of them it joins up with instead.
+    Corner {
+        position: Complex<isize>,
+        orientations: usize,
+    },
+}
+
+impl Defect {
+    fn position(&self) -> Complex<isize> {
+        match self {
+            Defect::Dangling { position, .. } | Defect::Corner { position, .. } => *position,
+        }
+    }
+}
+
+impl Display for Defect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let p = self.position();
+        write!(f, "({},{}): ", p.re, p.im)?;
+        match self {
+            Defect::Dangling { direction, .. } => {
+                let name = match *direction {
+                    NORTH => "north",
+                    SOUTH => "south",
+                    EAST => "east",
+                    _ => "west",
+                };
+                write!(f, "track leads nowhere to the {name}")
+            }
+            Defect::Corner {
+                orientations: 0, ..
+            } => write!(f, "corner doesn't join up either way"),
+            Defect::Corner { .. } => write!(f, "corner could join up either way"),
+        }
     }
 }
 
 struct Puzzle {
     carts: Vec<Cart>,
-    tracks: HashMap<Complex<isize>, Track>,
+    tracks: Tracks,
+    /// How many ticks have started so far.
+    tick: usize,
+    /// The crashes during the most recent tick.
+    crashes: Vec<Crash>,
+    /// Everything that has happened, if anyone asked.
+    events: Option<Vec<Event>>,
+    policy: CollisionPolicy,
+    /// What carts do at intersections, in order, over and over.
+    decisions: Vec<Decision>,
+    /// Where every cart is headed, if we're fast-forwarding.
+    schedule: Option<Schedule>,
+    /// The straight runs for the schedule to send carts along.
+    runs: Option<Runs>,
+}
+
+/// Position, velocity, previous position and next decision.
+type CartState = (Complex<isize>, Complex<isize>, Complex<isize>, usize);
+
+enum Snapshot {
+    State(Vec<CartState>),
+    Hash(u64),
+}
+
+/// The parts of a puzzle that change as it runs, so `run` can go back to
+/// where it started.
+#[derive(Clone)]
+struct Progress {
+    carts: Vec<Cart>,
+    tick: usize,
+    crashes: Vec<Crash>,
+    schedule: Option<Schedule>,
+}
+
+impl Puzzle {
+    fn with_dense_tracks(self) -> Self {
+        Self {
+            tracks: self.tracks.to_dense(),
+            ..self
+        }
+    }
+
+    fn with_event_log(self) -> Self {
+        Self {
+            events: Some(vec![]),
+            ..self
+        }
+    }
+
+    fn with_fast_forward(self) -> Self {
+        let runs = Runs::new(&self.tracks);
+        Self {
+            schedule: Some(Schedule::new(&runs, &self.carts, self.tick)),
+            runs: Some(runs),
+            ..self
+        }
+    }
+
+    fn with_policy(self, policy: CollisionPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    fn with_decisions(self, decisions: Vec<Decision>) -> Self {
+        assert!(
+            !decisions.is_empty(),
+            "carts need something to do at intersections"
+        );
+        Self { decisions, ..self }
+    }
+
+    fn log(&mut self, event: Event) {
+        if let Some(events) = self.events.as_mut() {
+            events.push(event);
+        }
+    }
+
+    /// Move every cart one step and report which of them crashed. Crashed
+    /// carts stay on the map; it's up to the caller whether to remove them.
+    fn tick_once(&mut self) -> Result<Vec<Crash>, SimulationError> {
+        self.tick += 1;
+        self.sort_carts();
+        let slots: Vec<usize> = (0..self.carts.len()).collect();
+        self.tick_carts(&slots)
+    }
+
+    /// Move the carts in these slots, in order, and check them for crashes
+    /// against each other.
+    fn tick_carts(&mut self, slots: &[usize]) -> Result<Vec<Crash>, SimulationError> {
+        let mut crashes: Vec<Crash> = vec![];
+
+        for &i in slots {
+            // Don't move a crashed cart (unless it is allowed to carry on).
+            let id = self.carts[i].id;
+            let stopped = matches!(
+                self.policy,
+                CollisionPolicy::StopAtFirst | CollisionPolicy::RemoveBoth
+            );
+            if stopped && crashes.iter().any(|crash| crash.carts.contains(&id)) {
+                continue;
+            }
+            let from = self.carts[i].position;
+            let decision = self.decisions[self.carts[i].next_decision];
+            self.carts[i].tick(&self.tracks, &self.decisions, self.tick)?;
+
+            if self.events.is_some() {
+                let (tick, cart, position) = (self.tick, self.carts[i].id, self.carts[i].position);
+                self.log(Event::Moved {
+                    tick,
+                    cart,
+                    from,
+                    to: position,
+                });
+                match self.tracks.get(&position) {
+                    Some(&track @ (Track::TurnSE | Track::TurnSW)) => self.log(Event::Turned {
+                        tick,
+                        cart,
+                        position,
+                        track,
+                    }),
+                    Some(Track::Intersection) => self.log(Event::Decided {
+                        tick,
+                        cart,
+                        position,
+                        decision,
+                    }),
+                    _ => {}
+                }
+            }
+
+            for &j in slots {
+                if i != j && self.carts[i].position == self.carts[j].position {
+                    let crash = Crash {
+                        position: self.carts[i].position,
+                        carts: [self.carts[i].id, self.carts[j].id],
+                    };
+                    self.log(Event::Collided {
+                        tick: self.tick,
+                        carts: crash.carts,
+                        position: crash.position,
+                    });
+                    crashes.push(crash);
+                    if self.policy == CollisionPolicy::Bounce {
+                        self.carts[i].turn_around();
+                        self.carts[j].turn_around();
+                    }
+                }
+            }
+        }
+
+        self.crashes.clone_from(&crashes);
+        Ok(crashes)
+    }
+
+    /// One tick, either way.
+    fn advance(&mut self) -> Result<Vec<Crash>, SimulationError> {
+        let Some(mut schedule) = self.schedule.take() else {
+            return self.tick_once();
+        };
+        let crashes = self.tick_scheduled(&mut schedule);
+        self.schedule = Some(schedule);
+        crashes
+    }
+
+    /// One tick of fast-forwarding: only the carts that get to the end of
+    /// their run, or might run into someone, move. They move in the same
+    /// order as they would if everyone moved, and nobody else could get in
+    /// their way, so the crashes come out just the same.
+    fn tick_scheduled(&mut self, schedule: &mut Schedule) -> Result<Vec<Crash>, SimulationError> {
+        self.tick += 1;
+        let now = self.tick;
+        for (power, d) in schedule.powers.iter_mut().zip(Runs::DIRECTIONS) {
+            *power = mul_mod(*power, weight(d));
+        }
+        schedule.moved.clear();
+        self.crashes.clear();
+        let mut ids = schedule.queue.remove(&now).unwrap_or_default();
+        ids.retain(|&id| schedule.legs[id].is_some_and(|leg| leg.wake == now));
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        ids.sort_unstable();
+        ids.dedup();
+
+        // Bring the carts up to date and sort them into the slots they
+        // already had between them, which keeps carts sharing a cell in the
+        // order they were in.
+        for &id in ids.iter() {
+            self.catch_up(schedule, id, now - 1);
+            schedule.stop(id);
+        }
+        let mut slots: Vec<usize> = ids.iter().map(|&id| schedule.slots[id]).collect();
+        slots.sort_unstable();
+        let mut carts: Vec<Cart> = slots.iter().map(|&i| self.carts[i].clone()).collect();
+        carts.sort_by_key(|cart| (cart.position.im, cart.position.re));
+        for (&i, cart) in slots.iter().zip(carts) {
+            schedule.slots[cart.id] = i;
+            self.carts[i] = cart;
+        }
+
+        let crashes = self.tick_carts(&slots)?;
+
+        let stopped = matches!(
+            self.policy,
+            CollisionPolicy::StopAtFirst | CollisionPolicy::RemoveBoth
+        );
+        for &i in slots.iter() {
+            let id = self.carts[i].id;
+            if stopped && crashes.iter().any(|crash| crash.carts.contains(&id)) {
+                continue;
+            }
+            let runs = self.runs.as_ref().expect("fast-forwarding needs runs");
+            schedule.start(runs, &self.carts, id, now);
+            schedule.moved.push(id);
+        }
+        Ok(crashes)
+    }
+
+    /// Move a fast-forwarding cart along its run to where it is on `tick`.
+    fn catch_up(&mut self, schedule: &mut Schedule, id: usize, tick: usize) {
+        let Some(leg) = schedule.legs[id].as_mut() else {
+            return;
+        };
+        if tick > leg.at {
+            let cart = &mut self.carts[schedule.slots[id]];
+            let from = cart.position;
+            cart.position += cart.velocity * (tick - leg.at) as isize;
+            cart.previous = cart.position - cart.velocity;
+            let to = cart.position;
+            leg.at = tick;
+            self.log(Event::Moved {
+                tick,
+                cart: id,
+                from,
+                to,
+            });
+        }
+    }
+
+    /// Bring every fast-forwarding cart up to date, for whoever wants to
+    /// look at them.
+    fn settle(&mut self) {
+        if let Some(mut schedule) = self.schedule.take() {
+            for id in 0..schedule.legs.len() {
+                self.catch_up(&mut schedule, id, self.tick);
+            }
+            self.schedule = Some(schedule);
+        }
+    }
+
+    /// Tick until the collision policy says we're done: the first crash for
+    /// `StopAtFirst`, otherwise until at most one cart is left. Carts that
+    /// bounce, pass through each other, or just never meet may never get
+    /// there, so we also watch for the whole configuration repeating.
+    ///
+    /// Remembering every state we've seen made the plain puzzle take two and
+    /// a half times as long, so this is Brent's algorithm instead: keep one
+    /// state, compare every tick against it (which usually stops at the first
+    /// cart), and replace it whenever the distance to it reaches the next
+    /// power of two. That finds the period; then we go back to the start to
+    /// find where the cycle begins.
+    fn run(&mut self) -> Result<Outcome, SimulationError> {
+        let start = self.progress();
+        let mut snapshot = self.snapshot();
+        let (mut taken, mut power) = (self.tick, 1);
+        loop {
+            if self.policy != CollisionPolicy::StopAtFirst && self.carts.len() <= 1 {
+                self.settle();
+                return Ok(Outcome::Resolved(self.carts.first().map(|cart| cart.id)));
+            }
+            if self.tick > taken {
+                let period = self.tick - taken;
+                // The hash can be wrong when fast-forwarding, but the states
+                // from going back over it can't.
+                if self.matches(&snapshot)
+                    && let Some(since) = self.cycle_start(&start, period)?
+                {
+                    self.settle();
+                    return Ok(Outcome::Cycle { since, period });
+                }
+                if period == power {
+                    snapshot = self.snapshot();
+                    taken = self.tick;
+                    power *= 2;
+                }
+            }
+            if let Some(crashes) = self.step()? {
+                self.settle();
+                return Ok(Outcome::Crashed(crashes));
+            }
+        }
+    }
+
+    /// One tick of `run`, with any crashes the policy stops at.
+    fn step(&mut self) -> Result<Option<Vec<Crash>>, SimulationError> {
+        let crashes = self.advance()?;
+        match self.policy {
+            CollisionPolicy::StopAtFirst if !crashes.is_empty() => return Ok(Some(crashes)),
+            CollisionPolicy::RemoveBoth => self.remove_crashed(&crashes),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// The first tick whose state comes round again `period` ticks later,
+    /// going back over everything from `start` with a second copy of the
+    /// carts `period` ticks ahead. `None` if it's not one we've passed yet,
+    /// which means it was a false alarm.
+    fn cycle_start(
+        &mut self,
+        start: &Progress,
+        period: usize,
+    ) -> Result<Option<usize>, SimulationError> {
+        let last = self.tick - period;
+        let events = self.events.take();
+        let mut here = start.clone();
+        self.swap(&mut here);
+        let mut behind = start.clone();
+        let since = self.chase(&mut behind, period, last);
+        self.swap(&mut here);
+        self.events = events;
+        since
+    }
+
+    /// Get `period` ticks ahead of `behind`, then tick both along until they
+    /// match, up to tick `last`.
+    fn chase(
+        &mut self,
+        behind: &mut Progress,
+        period: usize,
+        last: usize,
+    ) -> Result<Option<usize>, SimulationError> {
+        for _ in 0..period {
+            self.step()?;
+        }
+        loop {
+            let ahead = self.state();
+            self.swap(behind);
+            if self.state() == ahead {
+                return Ok(Some(self.tick));
+            }
+            if self.tick >= last {
+                return Ok(None);
+            }
+            self.step()?;
+            self.swap(behind);
+            self.step()?;
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            carts: self.carts.clone(),
+            tick: self.tick,
+            crashes: self.crashes.clone(),
+            schedule: self.schedule.clone(),
+        }
+    }
+
+    fn swap(&mut self, progress: &mut Progress) {
+        std::mem::swap(&mut self.carts, &mut progress.carts);
+        std::mem::swap(&mut self.tick, &mut progress.tick);
+        std::mem::swap(&mut self.crashes, &mut progress.crashes);
+        std::mem::swap(&mut self.schedule, &mut progress.schedule);
+    }
+
+    /// Everything that decides what happens next: where the carts are, where
+    /// they're going (and where they'd head if they bounced), what they'll do
+    /// at the next intersection, and the order they move in (which only
+    /// matters when two of them share a cell). The ids don't change anything,
+    /// so they're left out.
+    fn state(&mut self) -> Vec<CartState> {
+        self.settle();
+        let mut state: Vec<CartState> = self.carts.iter().map(Cart::state).collect();
+        state.sort_by_key(|&(position, ..)| (position.im, position.re));
+        state
+    }
+
+    /// What `run` holds on to while it waits for the state to come round
+    /// again. When fast-forwarding, most carts are out of date, so it makes
+    /// do with the hash.
+    fn snapshot(&mut self) -> Snapshot {
+        match &self.schedule {
+            Some(schedule) => Snapshot::Hash(schedule.hash(&self.carts)),
+            None => Snapshot::State(self.state()),
+        }
+    }
+
+    fn matches(&mut self, snapshot: &Snapshot) -> bool {
+        match snapshot {
+            Snapshot::Hash(hash) => self
+                .schedule
+                .as_ref()
+                .is_some_and(|schedule| schedule.hash(&self.carts) == *hash),
+            Snapshot::State(state) => {
+                // The next tick sorts them anyway.
+                self.sort_carts();
+                self.carts.len() == state.len()
+                    && self
+                        .carts
+                        .iter()
+                        .zip(state)
+                        .all(|(cart, s)| cart.state() == *s)
+            }
+        }
+    }
+
+    fn sort_carts(&mut self) {
+        // Does order matter? Yes. (Read the instructions!)
+        // Thank you https://www.reddit.com/r/adventofcode/comments/a8f32j/comment/ecarp8g/.
+        self.carts.sort_by(|c1, c2| {
+            let x1 = c1.position.re;
+            let x2 = c2.position.re;
+            let y1 = c1.position.im;
+            let y2 = c2.position.im;
+            (y1, x1).cmp(&(y2, x2))
+        });
+    }
+
+    fn svg(&self) -> Svg<'_> {
+        Svg(self)
+    }
+
+    /// Take every cart involved in one of these crashes off the map.
+    fn remove_crashed(&mut self, crashes: &[Crash]) {
+        // Most ticks, there's nothing to do and nothing to reallocate.
+        if crashes.is_empty() {
+            return;
+        }
+        let (removed, kept) = std::mem::take(&mut self.carts)
+            .into_iter()
+            .partition(|cart| crashes.iter().any(|crash| crash.carts.contains(&cart.id)));
+        self.carts = kept;
+        if let Some(schedule) = self.schedule.as_mut() {
+            for cart in removed.iter() {
+                schedule.stop(cart.id);
+                schedule.slots[cart.id] = usize::MAX;
+            }
+            for (i, cart) in self.carts.iter().enumerate() {
+                schedule.slots[cart.id] = i;
+            }
+        }
+        for cart in removed {
+            self.log(Event::Removed {
+                tick: self.tick,
+                cart: cart.id,
+                position: cart.position,
+            });
+        }
+    }
+
+    /// Check that every piece of track joins up with its neighbours, so that
+    /// a broken map is reported all at once, in reading order, instead of
+    /// whenever a cart happens to find the problem.
+    fn validate(&self) -> Result<(), Vec<Defect>> {
+        let joins = |p, direction| self.tracks.joins(p, direction);
+
+        let mut defects = Vec::new();
+        for (position, track) in self.tracks.iter() {
+            match track {
+                Track::TurnSE | Track::TurnSW => {
+                    // Where two loops touch, a corner can have more than two
+                    // neighbours pointing at it, and that's fine as long as
+                    // only one pair of them makes a corner.
+                    let orientations = track
+                        .orientations()
+                        .iter()
+                        .filter(|o| o.iter().all(|&d| joins(position, d)))
+                        .count();
+                    if orientations != 1 {
+                        defects.push(Defect::Corner {
+                            position,
+                            orientations,
+                        });
+                    }
+                }
+                Track::Vertical | Track::Horizontal | Track::Intersection => {
+                    for &direction in track.orientations()[0] {
+                        if !joins(position, direction) {
+                            defects.push(Defect::Dangling {
+                                position,
+                                direction,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        if defects.is_empty() {
+            Ok(())
+        } else {
+            defects.sort_by_key(|d| (d.position().im, d.position().re));
+            Err(defects)
+        }
+    }
 }
 
 impl Display for Puzzle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let rows = self.tracks.keys().map(|p| p.im).max().unwrap();
-        let cols = self.tracks.keys().map(|p| p.re).max().unwrap();
+        let rows = self.tracks.iter().map(|(p, _)| p.im).max().unwrap();
+        let cols = self.tracks.iter().map(|(p, _)| p.re).max().unwrap();
         let directions = HashMap::from([
             (Complex::new(-1isize, 0), '<'),
             (Complex::new(1, 0), '>'),
@@ -128,6 +1544,10 @@ impl Display for Puzzle {
         for row in 0..=rows {
             'column: for col in 0..=cols {
                 let p = Complex::new(col, row);
+                if self.crashes.iter().any(|crash| crash.position == p) {
+                    write!(f, "X")?;
+                    continue 'column;
+                }
                 for cart in self.carts.iter() {
                     if cart.position == p {
                         write!(f, "{}", directions.get(&cart.velocity).unwrap())?;
@@ -145,7 +1565,9 @@ impl Display for Puzzle {
     }
 }
 
-impl Solver<Vec<Complex<isize>>, Complex<isize>> for Puzzle {
+impl Solver<Result<Vec<Complex<isize>>, SimulationError>, Result<Complex<isize>, SimulationError>>
+    for Puzzle
+{
     fn new(input: &str) -> Self {
         let mut carts = Vec::new();
         let mut tracks = HashMap::new();
@@ -170,74 +1592,430 @@ impl Solver<Vec<Complex<isize>>, Complex<isize>> for Puzzle {
                     }
                     ' ' => {}
                     'v' => {
-                        carts.push(Cart::new(p, Complex::new(0, 1)));
+                        carts.push(Cart::new(carts.len(), p, Complex::new(0, 1)));
                         tracks.insert(p, Track::Vertical);
                     }
                     '^' => {
-                        carts.push(Cart::new(p, Complex::new(0, -1)));
+                        carts.push(Cart::new(carts.len(), p, Complex::new(0, -1)));
                         tracks.insert(p, Track::Vertical);
                     }
                     '<' => {
-                        carts.push(Cart::new(p, Complex::new(-1, 0)));
+                        carts.push(Cart::new(carts.len(), p, Complex::new(-1, 0)));
                         tracks.insert(p, Track::Horizontal);
                     }
                     '>' => {
-                        carts.push(Cart::new(p, Complex::new(1, 0)));
+                        carts.push(Cart::new(carts.len(), p, Complex::new(1, 0)));
                         tracks.insert(p, Track::Horizontal);
                     }
                     _ => panic!(),
                 }
             }
         }
-        Self { carts, tracks }
+        Self {
+            carts,
+            tracks: Tracks::Sparse(tracks),
+            tick: 0,
+            crashes: vec![],
+            events: None,
+            policy: CollisionPolicy::RemoveBoth,
+            decisions: vec![Decision::Left, Decision::Straight, Decision::Right],
+            schedule: None,
+            runs: None,
+        }
+    }
+
+    fn part1(&mut self) -> Result<Vec<Complex<isize>>, SimulationError> {
+        self.policy = CollisionPolicy::StopAtFirst;
+        match self.run()? {
+            Outcome::Crashed(crashes) => {
+                Ok(crashes.into_iter().map(|crash| crash.position).collect())
+            }
+            Outcome::Cycle { since, period } => {
+                Err(SimulationError::NeverResolves { since, period })
+            }
+            outcome => unreachable!("{outcome:?}"),
+        }
     }
 
-    fn part1(&mut self) -> Vec<Complex<isize>> {
-        let mut crash_sites = vec![];
+    fn part2(&mut self) -> Result<Complex<isize>, SimulationError> {
+        self.policy = CollisionPolicy::RemoveBoth;
+        match self.run()? {
+            Outcome::Resolved(Some(_)) => Ok(self.carts[0].position),
+            Outcome::Resolved(None) => Err(SimulationError::NoCartsLeft { tick: self.tick }),
+            Outcome::Cycle { since, period } => {
+                Err(SimulationError::NeverResolves { since, period })
+            }
+            outcome => unreachable!("{outcome:?}"),
+        }
+    }
+}
 
-        loop {
-            // Does order matter? Yes. (Read the instructions!)
-            // Thank you https://www.reddit.com/r/adventofcode/comments/a8f32j/comment/ecarp8g/.
-            self.carts.sort_by(|c1, c2| {
-                let x1 = c1.position.re;
-                let x2 = c2.position.re;
-                let y1 = c1.position.im;
-                let y2 = c2.position.im;
-                (y1, x1).cmp(&(y2, x2))
-            });
+/// The map as an SVG drawing: track in dark grey with rounded corners, every cart's
+/// path in its own colour, and a red ring wherever carts crashed. Paths and
+/// crashes come from the event log, so without one all we can show is where
+/// the carts are now.
+struct Svg<'a>(&'a Puzzle);
 
-            for i in 0..self.carts.len() {
-                // Don't move a crashed cart.
-                if crash_sites.contains(&self.carts[i].position) {
-                    continue;
+impl Svg<'_> {
+    /// Pixels per cell.
+    const SCALE: f64 = 10.0;
+
+    fn point(p: Complex<isize>) -> (f64, f64) {
+        (
+            (p.re as f64 + 0.5) * Self::SCALE,
+            (p.im as f64 + 0.5) * Self::SCALE,
+        )
+    }
+
+    /// Spread the cart colours around the colour wheel by the golden angle,
+    /// so neighbouring ids never look alike.
+    fn colour(cart: usize) -> String {
+        format!("hsl({:.0},70%,45%)", (cart as f64 * 137.508) % 360.0)
+    }
+}
+
+impl Display for Svg<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Svg(puzzle) = self;
+        let half = Self::SCALE / 2.0;
+        let rows = puzzle
+            .tracks
+            .iter()
+            .map(|(p, _)| p.im + 1)
+            .max()
+            .unwrap_or(0);
+        let cols = puzzle
+            .tracks
+            .iter()
+            .map(|(p, _)| p.re + 1)
+            .max()
+            .unwrap_or(0);
+        writeln!(
+            f,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = cols as f64 * Self::SCALE,
+            h = rows as f64 * Self::SCALE
+        )?;
+        writeln!(f, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+
+        // Straight pieces run edge to edge through the middle of the cell.
+        // Corners are quarter circles around the cell corner they bend
+        // towards, which is what makes a loop of them look like a racetrack.
+        write!(
+            f,
+            r#"<path fill="none" stroke="darkgrey" stroke-width="2" d=""#
+        )?;
+        for (p, track) in puzzle.tracks.iter() {
+            let (x, y) = Self::point(p);
+            let edge = |d: Complex<isize>| (x + d.re as f64 * half, y + d.im as f64 * half);
+            match track {
+                Track::TurnSE | Track::TurnSW => {
+                    let [a, b] = puzzle.tracks.orientation(p, track) else {
+                        unreachable!("corners join two neighbours")
+                    };
+                    let ((x1, y1), (x2, y2)) = (edge(*a), edge(*b));
+                    let (cx, cy) = edge(a + b);
+                    // y points down, so a positive cross product is clockwise.
+                    let sweep = (x1 - cx) * (y2 - cy) - (y1 - cy) * (x2 - cx) > 0.0;
+                    write!(f, "M{x1} {y1}A{half} {half} 0 0 {} {x2} {y2}", sweep as u8)?;
                 }
-                self.carts[i].tick(&self.tracks);
-                for j in 0..self.carts.len() {
-                    if i != j && self.carts[i].position == self.carts[j].position {
-                        crash_sites.push(self.carts[i].position);
+                _ => {
+                    for pair in puzzle.tracks.orientation(p, track).chunks(2) {
+                        let ((x1, y1), (x2, y2)) = (edge(pair[0]), edge(pair[1]));
+                        write!(f, "M{x1} {y1}L{x2} {y2}")?;
                     }
                 }
             }
+        }
+        writeln!(f, r#""/>"#)?;
 
-            if !crash_sites.is_empty() {
-                return crash_sites;
+        let mut paths: Vec<(usize, Vec<Complex<isize>>)> = vec![];
+        let mut crashes: Vec<(Complex<isize>, String)> = vec![];
+        for event in puzzle.events.iter().flatten() {
+            match event {
+                Event::Moved { cart, from, to, .. } => {
+                    match paths.iter_mut().find(|(id, _)| id == cart) {
+                        Some((_, path)) => path.push(*to),
+                        None => paths.push((*cart, vec![*from, *to])),
+                    }
+                }
+                Event::Collided {
+                    tick,
+                    carts,
+                    position,
+                } => crashes.push((
+                    *position,
+                    format!("tick {tick}: cart {} hit cart {}", carts[0], carts[1]),
+                )),
+                _ => {}
             }
         }
+        if puzzle.events.is_none() {
+            crashes.extend(puzzle.crashes.iter().map(|crash| {
+                (
+                    crash.position,
+                    format!("cart {} hit cart {}", crash.carts[0], crash.carts[1]),
+                )
+            }));
+        }
+        paths.sort_by_key(|(id, _)| *id);
+
+        for (cart, path) in paths.iter() {
+            let points: Vec<String> = path
+                .iter()
+                .map(|&p| {
+                    let (x, y) = Self::point(p);
+                    format!("{x},{y}")
+                })
+                .collect();
+            writeln!(
+                f,
+                r#"<polyline fill="none" stroke="{}" stroke-width="1.5" stroke-opacity="0.8" points="{}"><title>cart {cart}</title></polyline>"#,
+                Self::colour(*cart),
+                points.join(" ")
+            )?;
+        }
+        for cart in puzzle.carts.iter() {
+            let (x, y) = Self::point(cart.position);
+            writeln!(
+                f,
+                r#"<circle cx="{x}" cy="{y}" r="{}" fill="{}"><title>cart {}</title></circle>"#,
+                half * 0.6,
+                Self::colour(cart.id),
+                cart.id
+            )?;
+        }
+        for (position, title) in crashes.iter() {
+            let (x, y) = Self::point(*position);
+            writeln!(
+                f,
+                r#"<circle cx="{x}" cy="{y}" r="{}" fill="none" stroke="red" stroke-width="2"><title>{title}</title></circle>"#,
+                half * 0.8
+            )?;
+        }
+        writeln!(f, "</svg>")
+    }
+}
+
+/// xorshift64, so the random maps are the same on every machine.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn shuffle<T>(&mut self, v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            v.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// How `random_map` lays out its loops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    /// Every row and column holds at most one side of one rectangle, and
+    /// only even ones at that, so sides never run alongside each other:
+    /// where two rectangles meet, it's a side crossing a side, which is a
+    /// `+`.
+    Spaced,
+    /// Rectangles go wherever they fit. Sides can run right next to each
+    /// other, and corners can touch, like `\/`, as long as every corner
+    /// still only joins up one way.
+    Packed,
+}
+
+/// A map of `loops` overlapping rectangles with `carts` carts on them.
+fn random_map(
+    seed: u64,
+    width: usize,
+    height: usize,
+    loops: usize,
+    carts: usize,
+    layout: Layout,
+) -> String {
+    // xorshift gets stuck on zero, so mix the seed up a bit first.
+    let mut rng = XorShift(seed ^ 0x9e37_79b9_7f4a_7c15);
+    let mut grid = vec![vec![' '; width]; height];
+
+    match layout {
+        Layout::Spaced => {
+            assert!(
+                4 * loops <= width.min(height) + 1,
+                "not enough room for {loops} loops"
+            );
+            let mut xs: Vec<usize> = (0..width).step_by(2).collect();
+            let mut ys: Vec<usize> = (0..height).step_by(2).collect();
+            rng.shuffle(&mut xs);
+            rng.shuffle(&mut ys);
+            for (x, y) in xs.chunks(2).zip(ys.chunks(2)).take(loops) {
+                let (left, right) = (x[0].min(x[1]), x[0].max(x[1]));
+                let (top, bottom) = (y[0].min(y[1]), y[0].max(y[1]));
+                draw_rectangle(&mut grid, left, right, top, bottom);
+            }
+        }
+        Layout::Packed => {
+            assert!(width >= 2 && height >= 2, "no room for any loops");
+            let mut corners: Vec<(usize, usize)> = vec![];
+            let mut placed = 0;
+            for _ in 0..1000 * loops {
+                if placed == loops {
+                    break;
+                }
+                let w = 1 + rng.below((width / 2).max(1));
+                let h = 1 + rng.below((height / 2).max(1));
+                // Half the time, start right next to a corner we already
+                // have, to give corners a chance to touch.
+                let (left, top) = if !corners.is_empty() && rng.below(2) == 0 {
+                    let (y, x) = corners[rng.below(corners.len())];
+                    let left = if rng.below(2) == 0 {
+                        Some(x + 1)
+                    } else {
+                        x.checked_sub(w + 1)
+                    };
+                    let top = if rng.below(2) == 0 {
+                        Some(y)
+                    } else {
+                        y.checked_sub(h)
+                    };
+                    let (Some(left), Some(top)) = (left, top) else {
+                        continue;
+                    };
+                    (left, top)
+                } else {
+                    (rng.below(width - 1), rng.below(height - 1))
+                };
+                let (right, bottom) = (left + w, top + h);
+                if right >= width || bottom >= height {
+                    continue;
+                }
+                if place_rectangle(&mut grid, left, right, top, bottom) {
+                    corners.extend([(top, left), (top, right), (bottom, left), (bottom, right)]);
+                    placed += 1;
+                }
+            }
+            assert_eq!(placed, loops, "could only fit {placed} of {loops} loops");
+        }
+    }
+
+    let mut straights: Vec<(usize, usize)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (y, x)))
+        .filter(|&(y, x)| matches!(grid[y][x], '-' | '|'))
+        .collect();
+    assert!(
+        carts <= straights.len(),
+        "not enough track for {carts} carts"
+    );
+    rng.shuffle(&mut straights);
+    for &(y, x) in straights.iter().take(carts) {
+        let forwards = rng.below(2) == 0;
+        grid[y][x] = match (grid[y][x], forwards) {
+            ('-', true) => '>',
+            ('-', false) => '<',
+            (_, true) => 'v',
+            (_, false) => '^',
+        };
+    }
+
+    grid.into_iter()
+        .map(|row| row.into_iter().collect::<String>() + "\n")
+        .collect()
+}
+
+/// Draw a rectangle, turning any side it crosses into an intersection.
+fn draw_rectangle(grid: &mut [Vec<char>], left: usize, right: usize, top: usize, bottom: usize) {
+    for row in [top, bottom] {
+        for cell in &mut grid[row][left + 1..right] {
+            *cell = if *cell == '|' { '+' } else { '-' };
+        }
+    }
+    for row in grid.iter_mut().take(bottom).skip(top + 1) {
+        for col in [left, right] {
+            row[col] = if row[col] == '-' { '+' } else { '|' };
+        }
     }
+    grid[top][left] = '/';
+    grid[top][right] = '\\';
+    grid[bottom][left] = '\\';
+    grid[bottom][right] = '/';
+}
 
-    fn part2(&mut self) -> Complex<isize> {
-        while self.carts.len() > 1 {
-            let crash_site = self.part1();
-            self.carts
-                .retain(|cart| !crash_site.contains(&cart.position));
+/// Draw a rectangle if it fits: its corners have to go on empty cells, its
+/// sides can only cross other sides at right angles, and every corner near
+/// it still has to join up exactly one way. Otherwise leave the grid alone.
+fn place_rectangle(
+    grid: &mut [Vec<char>],
+    left: usize,
+    right: usize,
+    top: usize,
+    bottom: usize,
+) -> bool {
+    let mut cells = vec![(top, left), (top, right), (bottom, left), (bottom, right)];
+    if cells.iter().any(|&(y, x)| grid[y][x] != ' ') {
+        return false;
+    }
+    let row_fits = |y: usize| {
+        grid[y][left + 1..right]
+            .iter()
+            .all(|&c| matches!(c, ' ' | '|'))
+    };
+    let column_fits = |x: usize| {
+        grid[top + 1..bottom]
+            .iter()
+            .all(|row| matches!(row[x], ' ' | '-'))
+    };
+    if !(row_fits(top) && row_fits(bottom) && column_fits(left) && column_fits(right)) {
+        return false;
+    }
+    cells.extend((left + 1..right).flat_map(|x| [(top, x), (bottom, x)]));
+    cells.extend((top + 1..bottom).flat_map(|y| [(y, left), (y, right)]));
+
+    let before: Vec<char> = cells.iter().map(|&(y, x)| grid[y][x]).collect();
+    draw_rectangle(grid, left, right, top, bottom);
+
+    let track = |grid: &[Vec<char>], p: Complex<isize>| {
+        let (Ok(x), Ok(y)) = (usize::try_from(p.re), usize::try_from(p.im)) else {
+            return None;
+        };
+        grid.get(y)
+            .and_then(|row| row.get(x))
+            .and_then(|&c| Track::from(c))
+    };
+    let fits = cells.iter().all(|&(y, x)| {
+        let p = Complex::new(x as isize, y as isize);
+        [Complex::new(0, 0), NORTH, SOUTH, EAST, WEST]
+            .into_iter()
+            .all(|d| match track(grid, p + d) {
+                Some(corner @ (Track::TurnSE | Track::TurnSW)) => {
+                    let joins = |o: &&&[Complex<isize>]| {
+                        o.iter()
+                            .all(|&e| track(grid, p + d + e).is_some_and(|n| n.can_connect(-e)))
+                    };
+                    corner.orientations().iter().filter(joins).count() == 1
+                }
+                _ => true,
+            })
+    });
+    if !fits {
+        for (&(y, x), &c) in cells.iter().zip(&before) {
+            grid[y][x] = c;
         }
-        assert_eq!(self.carts.len(), 1);
-        self.carts[0].position
     }
+    fits
 }
 
 #[cfg(test)]
 mod mine_cart_madness {
+    use std::time::Instant;
+
     use super::*;
 
     const SAMPLE1: &str = include_str!("../../samples/day13-1.txt");
@@ -246,18 +2024,625 @@ mod mine_cart_madness {
 
     #[test]
     fn test1() {
-        assert_eq!(Puzzle::new(SAMPLE1).part1(), vec![Complex::new(7, 3)])
+        assert_eq!(Puzzle::new(SAMPLE1).part1(), Ok(vec![Complex::new(7, 3)]))
     }
 
     #[test]
     fn test2() {
-        assert_eq!(Puzzle::new(SAMPLE2).part2(), Complex::new(6, 4))
+        assert_eq!(Puzzle::new(SAMPLE2).part2(), Ok(Complex::new(6, 4)))
     }
 
     #[test]
     /// Really needed this extra test case! Thank you!
     /// https://www.reddit.com/r/adventofcode/comments/a8f32j/comment/ecdqxrx/
     fn extra() {
-        assert_eq!(Puzzle::new(SAMPLE3).part1(), vec![Complex::new(0, 1)])
+        assert_eq!(Puzzle::new(SAMPLE3).part1(), Ok(vec![Complex::new(0, 1)]))
+    }
+
+    #[test]
+    fn dense_tracks() {
+        assert_eq!(
+            Puzzle::new(SAMPLE1).with_dense_tracks().part1(),
+            Ok(vec![Complex::new(7, 3)])
+        );
+        assert_eq!(
+            Puzzle::new(SAMPLE2).with_dense_tracks().part2(),
+            Ok(Complex::new(6, 4))
+        );
+        assert_eq!(
+            Puzzle::new(SAMPLE3).with_dense_tracks().part1(),
+            Ok(vec![Complex::new(0, 1)])
+        );
+        let p = Puzzle::new(SAMPLE2);
+        assert_eq!(p.to_string(), p.with_dense_tracks().to_string())
+    }
+
+    #[test]
+    fn valid_samples() {
+        // Two loops whose corners touch.
+        let touching = "/---\\/---\\\n|   ||   |\n\\---/\\---/\n";
+        for sample in [SAMPLE1, SAMPLE2, SAMPLE3, touching] {
+            assert_eq!(Puzzle::new(sample).validate(), Ok(()));
+            assert_eq!(Puzzle::new(sample).with_dense_tracks().validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn defects() {
+        let p = Puzzle::new(
+            "/-\\
+| |-
+\\-/
+ ++",
+        );
+        let defects = p.validate().unwrap_err();
+        assert_eq!(
+            defects,
+            vec![
+                Defect::Dangling {
+                    position: Complex::new(3, 1),
+                    direction: Complex::new(1, 0)
+                },
+                Defect::Dangling {
+                    position: Complex::new(3, 1),
+                    direction: Complex::new(-1, 0)
+                },
+                Defect::Dangling {
+                    position: Complex::new(1, 3),
+                    direction: Complex::new(0, -1)
+                },
+                Defect::Dangling {
+                    position: Complex::new(1, 3),
+                    direction: Complex::new(0, 1)
+                },
+                Defect::Dangling {
+                    position: Complex::new(1, 3),
+                    direction: Complex::new(-1, 0)
+                },
+                Defect::Dangling {
+                    position: Complex::new(2, 3),
+                    direction: Complex::new(0, -1)
+                },
+                Defect::Dangling {
+                    position: Complex::new(2, 3),
+                    direction: Complex::new(0, 1)
+                },
+                Defect::Dangling {
+                    position: Complex::new(2, 3),
+                    direction: Complex::new(1, 0)
+                },
+            ]
+        );
+        assert_eq!(
+            defects[0].to_string(),
+            "(3,1): track leads nowhere to the east"
+        );
+
+        // A corner on its own, and one in the middle of a crossroads.
+        assert_eq!(
+            Puzzle::new("/").validate(),
+            Err(vec![Defect::Corner {
+                position: Complex::new(0, 0),
+                orientations: 0
+            }])
+        );
+        let crossroads = Puzzle::new(" |\n-/-\n |").validate().unwrap_err();
+        assert!(crossroads.contains(&Defect::Corner {
+            position: Complex::new(1, 1),
+            orientations: 2
+        }));
+        assert_eq!(
+            crossroads[0].to_string(),
+            "(1,0): track leads nowhere to the north"
+        );
+
+        // Corners on either side of a straight piece, both bending away
+        // from it, so a cart on it runs straight off the rails.
+        assert_eq!(
+            Puzzle::new("/-\\>/--\\\n| | |  |\n\\-/ \\--/").validate(),
+            Err(vec![
+                Defect::Dangling {
+                    position: Complex::new(3, 0),
+                    direction: EAST
+                },
+                Defect::Dangling {
+                    position: Complex::new(3, 0),
+                    direction: WEST
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn single_steps() {
+        let mut p = Puzzle::new(SAMPLE1);
+        assert_eq!(p.tick_once(), Ok(vec![]));
+        assert_eq!(p.tick, 1);
+        for _ in 1..13 {
+            assert_eq!(p.tick_once(), Ok(vec![]));
+        }
+        assert_eq!(
+            p.tick_once(),
+            Ok(vec![Crash {
+                position: Complex::new(7, 3),
+                carts: [0, 1]
+            }])
+        );
+        assert_eq!(p.to_string().lines().nth(3), Some("| | |  X |  |"))
+    }
+
+    #[test]
+    fn stable_ids() {
+        let mut p = Puzzle::new(SAMPLE2);
+        assert_eq!(p.part2(), Ok(Complex::new(6, 4)));
+        assert_eq!(p.carts[0].id, 8);
+
+        // The same crashes, whichever way the carts were listed to begin with.
+        let mut q = Puzzle::new(SAMPLE2);
+        q.carts.reverse();
+        let first = |p: Puzzle| p.with_policy(CollisionPolicy::StopAtFirst).run();
+        assert_eq!(first(q), first(Puzzle::new(SAMPLE2)));
+        assert_eq!(
+            first(Puzzle::new(SAMPLE3)),
+            Ok(Outcome::Crashed(vec![Crash {
+                position: Complex::new(0, 1),
+                carts: [0, 1]
+            }]))
+        )
+    }
+
+    #[test]
+    fn bounce() {
+        let mut p = Puzzle::new("/->-<-\\\n\\-----/").with_policy(CollisionPolicy::Bounce);
+        assert_eq!(p.tick_once().unwrap().len(), 1);
+        assert_eq!(p.to_string(), "/--X--\\\n\\-----/\n");
+        p.tick_once().unwrap();
+        assert_eq!(p.to_string(), "/-<->-\\\n\\-----/\n");
+
+        let mut p = Puzzle::new("/>\\\n| ^\n\\-/").with_policy(CollisionPolicy::Bounce);
+        assert_eq!(p.tick_once().unwrap().len(), 1);
+        p.tick_once().unwrap();
+        assert_eq!(p.to_string(), "/<\\\n| v\n\\-/\n");
+    }
+
+    #[test]
+    fn pass_through() {
+        let mut p = Puzzle::new("/->-<-\\\n\\-----/").with_policy(CollisionPolicy::PassThrough);
+        assert_eq!(p.tick_once().unwrap().len(), 1);
+        p.tick_once().unwrap();
+        assert_eq!(p.to_string(), "/-<->-\\\n\\-----/\n");
+        assert_eq!(p.carts.len(), 2)
+    }
+
+    #[test]
+    fn decision_cycle() {
+        let mut p =
+            Puzzle::new("->+-+-").with_decisions(vec![Decision::Straight, Decision::Reverse]);
+        p.tick_once().unwrap();
+        assert_eq!(p.carts[0].velocity, EAST);
+        p.tick_once().unwrap();
+        p.tick_once().unwrap();
+        assert_eq!(p.to_string(), "--+-<-\n");
+        p.tick_once().unwrap();
+        p.tick_once().unwrap();
+        assert_eq!(p.carts[0].velocity, WEST);
+        assert_eq!(p.carts[0].next_decision, 1);
+    }
+
+    #[test]
+    fn separate_loops() {
+        let map = "/>\\ /<\\\n\\-/ \\-/";
+        assert_eq!(
+            Puzzle::new(map).part2(),
+            Err(SimulationError::NeverResolves {
+                since: 0,
+                period: 6
+            })
+        );
+        assert!(Puzzle::new(map).part1().is_err());
+    }
+
+    #[test]
+    fn endless_bouncing() {
+        let mut p = Puzzle::new("/->-<-\\\n\\-----/").with_policy(CollisionPolicy::Bounce);
+        assert_eq!(
+            p.run(),
+            Ok(Outcome::Cycle {
+                since: 0,
+                period: 14
+            })
+        );
+        let mut p = Puzzle::new("/->-<-\\\n\\-----/").with_policy(CollisionPolicy::PassThrough);
+        assert_eq!(
+            p.run(),
+            Ok(Outcome::Cycle {
+                since: 0,
+                period: 14
+            })
+        );
+    }
+
+    /// Brent's algorithm has to find the same cycle as remembering every
+    /// state would.
+    #[test]
+    fn cycle_detection() {
+        let mut cycles = 0;
+        for seed in 0..20 {
+            let map = random_map(seed, 40, 30, 6, 9, Layout::Spaced);
+            let mut p = Puzzle::new(&map);
+            let mut seen = HashMap::new();
+            let expected = loop {
+                if p.carts.len() <= 1 {
+                    break None;
+                }
+                if let Some(since) = seen.insert(p.state(), p.tick) {
+                    cycles += 1;
+                    break Some(Outcome::Cycle {
+                        since,
+                        period: p.tick - since,
+                    });
+                }
+                p.step().unwrap();
+            };
+            match (Puzzle::new(&map).run().unwrap(), expected) {
+                (outcome, Some(cycle)) => assert_eq!(outcome, cycle, "seed {seed}"),
+                (outcome, None) => assert!(matches!(outcome, Outcome::Resolved(_))),
+            }
+        }
+        assert!(cycles > 0);
+    }
+
+    #[test]
+    fn event_log() {
+        let mut p = Puzzle::new(SAMPLE2).with_event_log();
+        p.part2().unwrap();
+        let events = p.events.unwrap();
+        assert_eq!(
+            events[0],
+            Event::Moved {
+                tick: 1,
+                cart: 0,
+                from: Complex::new(1, 0),
+                to: Complex::new(2, 0)
+            }
+        );
+        assert_eq!(
+            events[0].to_json(),
+            r#"{"tick":1,"event":"moved","cart":0,"from":[1,0],"to":[2,0]}"#
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Collided { .. }))
+                .count(),
+            4
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Removed { .. }))
+                .count(),
+            8
+        );
+        let turn = events
+            .iter()
+            .find(|e| {
+                matches!(
+                    e,
+                    Event::Turned {
+                        track: Track::TurnSW,
+                        ..
+                    }
+                )
+            })
+            .unwrap();
+        assert!(turn.to_json().ends_with(r#""track":"\\"}"#));
+        let decision = events
+            .iter()
+            .find(|e| matches!(e, Event::Decided { .. }))
+            .unwrap();
+        assert!(decision.to_json().ends_with(r#""decision":"left"}"#));
+    }
+
+    #[test]
+    fn svg() {
+        let mut p = Puzzle::new(SAMPLE1).with_event_log();
+        p.part1().unwrap();
+        let svg = p.svg().to_string();
+        let corners = SAMPLE1.matches(['/', '\\']).count();
+        assert_eq!(svg.matches("A5 5 0 0").count(), corners);
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains(r#"<circle cx="75" cy="35" r="4" fill="none" stroke="red""#));
+        // The first corner bends around the top left of the map.
+        assert!(svg.contains("M5 10A5 5 0 0 1 10 5"));
+
+        // No log, no paths, but the carts are still there.
+        let svg = Puzzle::new(SAMPLE1).svg().to_string();
+        assert_eq!(svg.matches("<polyline").count(), 0);
+        assert_eq!(svg.matches("<circle").count(), 2);
+    }
+
+    #[test]
+    fn straight_runs() {
+        let p = Puzzle::new(SAMPLE1);
+        let runs = Runs::new(&p.tracks);
+        assert_eq!(runs.get(Complex::new(0, 0), EAST), 3);
+        assert_eq!(runs.get(Complex::new(0, 0), SOUTH), 3);
+        assert_eq!(runs.get(Complex::new(2, 2), EAST), 1);
+        assert_eq!(runs.get(Complex::new(3, 2), EAST), 0);
+        assert_eq!(runs.get(Complex::new(12, 1), WEST), 4);
+        assert_eq!(runs.get(Complex::new(7, 1), NORTH), 0);
+    }
+
+    /// Skipping along has to give exactly the same answers as ticking, on
+    /// the same tick, down to where a cycle starts.
+    #[test]
+    fn fast_forward() {
+        let maps = [SAMPLE1, SAMPLE2, SAMPLE3]
+            .map(String::from)
+            .into_iter()
+            .chain([racetrack(50, 3)])
+            .chain((0..50).map(|seed| random_map(seed, 40, 30, 6, 9, Layout::Spaced)))
+            .chain((0..50).map(|seed| random_map(seed, 20, 15, 6, 7, Layout::Packed)));
+        for map in maps {
+            let mut slow = Puzzle::new(&map);
+            let mut fast = Puzzle::new(&map).with_fast_forward();
+            assert_eq!(slow.part1(), fast.part1(), "\n{map}");
+            assert_eq!(slow.tick, fast.tick, "\n{map}");
+
+            // Not every map has a part 2 answer, so compare how they end.
+            let mut slow = Puzzle::new(&map).with_event_log();
+            let mut fast = Puzzle::new(&map).with_fast_forward().with_event_log();
+            assert_eq!(slow.run(), fast.run(), "\n{map}");
+            assert_eq!(slow.tick, fast.tick, "\n{map}");
+            assert_eq!(slow.to_string(), fast.to_string(), "\n{map}");
+            assert_eq!(
+                step_by_step(slow.events.unwrap()),
+                step_by_step(fast.events.unwrap()),
+                "\n{map}"
+            );
+        }
+    }
+
+    /// Crashes that don't stop anything are where carts end up sharing a
+    /// cell, and the order they move in starts to matter.
+    #[test]
+    fn fast_forward_policies() {
+        let maps = [SAMPLE1.to_string(), racetrack(12, 2)]
+            .into_iter()
+            .chain((0..20).map(|seed| random_map(seed, 12, 10, 3, 5, Layout::Packed)));
+        for map in maps {
+            for policy in [CollisionPolicy::Bounce, CollisionPolicy::PassThrough] {
+                let mut slow = Puzzle::new(&map).with_policy(policy);
+                let mut fast = Puzzle::new(&map).with_policy(policy).with_fast_forward();
+                assert_eq!(slow.run(), fast.run(), "{policy:?}\n{map}");
+                assert_eq!(slow.tick, fast.tick, "{policy:?}\n{map}");
+                assert_eq!(slow.to_string(), fast.to_string(), "{policy:?}\n{map}");
+            }
+        }
+    }
+
+    /// Split fast-forwarded moves back into single steps, and put the log in
+    /// an order that doesn't depend on when carts got looked at.
+    fn step_by_step(events: Vec<Event>) -> Vec<String> {
+        let mut steps: Vec<(usize, usize, String)> = vec![];
+        for event in events {
+            match event {
+                Event::Moved {
+                    tick,
+                    cart,
+                    from,
+                    to,
+                } => {
+                    let d = to - from;
+                    let n = d.re.abs() + d.im.abs();
+                    let v = d / n.max(1);
+                    for k in 0..n {
+                        let step = Event::Moved {
+                            tick: tick + 1 + k as usize - n as usize,
+                            cart,
+                            from: from + v * k,
+                            to: from + v * (k + 1),
+                        };
+                        steps.push((
+                            tick + 1 + k as usize - n as usize,
+                            cart,
+                            format!("{step:?}"),
+                        ));
+                    }
+                }
+                Event::Turned { tick, cart, .. }
+                | Event::Decided { tick, cart, .. }
+                | Event::Removed { tick, cart, .. } => {
+                    steps.push((tick, cart, format!("{event:?}")))
+                }
+                Event::Collided { tick, carts, .. } => {
+                    steps.push((tick, carts[0], format!("{event:?}")))
+                }
+            }
+        }
+        steps.sort();
+        steps.into_iter().map(|(_, _, step)| step).collect()
+    }
+
+    #[test]
+    fn fast_forward_events() {
+        let mut p = Puzzle::new("/>-----\\\n|      |\n|      |\n\\------/")
+            .with_fast_forward()
+            .with_event_log();
+        // A long way to the first corner, and nobody else around to look
+        // out for, so nothing happens until it gets there.
+        for _ in 0..4 {
+            p.advance().unwrap();
+        }
+        assert_eq!(p.events.as_ref().unwrap(), &vec![]);
+        // Onto the corner is a tick of its own, then down the side.
+        for _ in 0..5 {
+            p.advance().unwrap();
+        }
+        assert_eq!(p.tick, 9);
+        p.settle();
+        assert_eq!(p.to_string(), "/------\\\n|      |\n|      |\n\\------<\n");
+        let turned = |tick, position, track| Event::Turned {
+            tick,
+            cart: 0,
+            position,
+            track,
+        };
+        let moved = |tick, from, to| Event::Moved {
+            tick,
+            cart: 0,
+            from,
+            to,
+        };
+        assert_eq!(
+            p.events.unwrap(),
+            vec![
+                moved(5, Complex::new(1, 0), Complex::new(6, 0)),
+                moved(6, Complex::new(6, 0), Complex::new(7, 0)),
+                turned(6, Complex::new(7, 0), Track::TurnSW),
+                moved(8, Complex::new(7, 0), Complex::new(7, 2)),
+                moved(9, Complex::new(7, 2), Complex::new(7, 3)),
+                turned(9, Complex::new(7, 3), Track::TurnSE),
+            ]
+        );
+    }
+
+    #[test]
+    fn off_track() {
+        assert_eq!(
+            Puzzle::new("/->--\n|\n").part1(),
+            Err(SimulationError::OffTrack {
+                cart: 0,
+                tick: 3,
+                position: Complex::new(5, 0)
+            })
+        )
+    }
+
+    #[test]
+    fn wrong_axis() {
+        assert_eq!(
+            Puzzle::new("->|\n\n  <-|").with_dense_tracks().part2(),
+            Err(SimulationError::WrongAxis {
+                cart: 0,
+                tick: 1,
+                position: Complex::new(2, 0),
+                velocity: Complex::new(1, 0)
+            })
+        )
+    }
+
+    #[test]
+    fn no_carts_left() {
+        assert_eq!(
+            Puzzle::new("/->-<-\\\n\\-----/").part2(),
+            Err(SimulationError::NoCartsLeft { tick: 1 })
+        );
+        assert_eq!(
+            Puzzle::new("/-\\\n\\-/").part2(),
+            Err(SimulationError::NoCartsLeft { tick: 0 })
+        )
+    }
+
+    /// A long, flat loop with `2k + 1` carts. The `k + 1` clockwise carts
+    /// start bunched up on the top and the `k` counter-clockwise carts on the
+    /// bottom, so they annihilate in pairs somewhere on the far side and the
+    /// last clockwise cart is left alone.
+    fn racetrack(width: usize, k: usize) -> String {
+        let mut top: Vec<char> = format!("/{}\\", "-".repeat(width - 2)).chars().collect();
+        let mut bottom: Vec<char> = format!("\\{}/", "-".repeat(width - 2)).chars().collect();
+        for i in 0..=k {
+            top[1 + 2 * i] = '>';
+        }
+        for i in 0..k {
+            bottom[1 + 2 * i] = '>';
+        }
+        let middle = format!("|{}|", " ".repeat(width - 2));
+        let top: String = top.into_iter().collect();
+        let bottom: String = bottom.into_iter().collect();
+        format!("{top}\n{middle}\n{bottom}\n")
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn bench_part2() {
+        let map = racetrack(200_000, 10);
+        let start = Instant::now();
+        let sparse = Puzzle::new(&map).part2();
+        println!("HashMap tracks: {:?}", start.elapsed());
+        let start = Instant::now();
+        let dense = Puzzle::new(&map).with_dense_tracks().part2();
+        println!("Dense tracks: {:?}", start.elapsed());
+        let start = Instant::now();
+        let fast = Puzzle::new(&map)
+            .with_dense_tracks()
+            .with_fast_forward()
+            .part2();
+        println!("Fast-forward: {:?}", start.elapsed());
+        assert_eq!(sparse, dense);
+        assert_eq!(dense, fast);
+    }
+
+    #[test]
+    fn random_maps() {
+        let mut touching = 0;
+        for seed in 0..100 {
+            for map in [
+                random_map(seed, 40, 30, 6, 9, Layout::Spaced),
+                random_map(seed, 20, 15, 6, 7, Layout::Packed),
+            ] {
+                let mut p = Puzzle::new(&map);
+                assert_eq!(p.validate(), Ok(()), "seed {seed}\n{map}");
+                // Corners with more neighbours pointing at them than they
+                // join up with, which only happens where loops touch.
+                touching += p
+                    .tracks
+                    .iter()
+                    .filter(|(position, track)| {
+                        matches!(track, Track::TurnSE | Track::TurnSW)
+                            && [NORTH, SOUTH, EAST, WEST]
+                                .into_iter()
+                                .filter(|&d| p.tracks.connects(*position, d))
+                                .count()
+                                > 2
+                    })
+                    .count();
+                // Carts on loops nobody else uses may go round forever, and
+                // three carts can crash at once, but they all have to stay
+                // on the rails.
+                match p.part2() {
+                    Ok(_)
+                    | Err(SimulationError::NeverResolves { .. })
+                    | Err(SimulationError::NoCartsLeft { .. }) => {}
+                    Err(e) => panic!("seed {seed}: {e}\n{map}"),
+                }
+            }
+        }
+        assert!(touching > 0);
+        assert_eq!(
+            random_map(7, 40, 30, 6, 9, Layout::Spaced),
+            random_map(7, 40, 30, 6, 9, Layout::Spaced)
+        );
+        assert_eq!(
+            random_map(7, 20, 15, 6, 7, Layout::Packed),
+            random_map(7, 20, 15, 6, 7, Layout::Packed)
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn bench_random_map() {
+        let map = random_map(2018, 2000, 2000, 500, 999, Layout::Spaced);
+        let mut slow = Puzzle::new(&map).with_dense_tracks();
+        assert_eq!(slow.validate(), Ok(()));
+        let start = Instant::now();
+        let result = slow.part2();
+        println!("{} ticks, {:?}: {result:?}", slow.tick, start.elapsed());
+        let mut fast = Puzzle::new(&map).with_dense_tracks().with_fast_forward();
+        let start = Instant::now();
+        assert_eq!(fast.part2(), result);
+        println!("Fast-forward: {:?}", start.elapsed());
+        assert_eq!(fast.tick, slow.tick);
     }
 }
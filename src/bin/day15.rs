@@ -1,7 +1,8 @@
 use std::{
-    cmp::Reverse,
-    collections::{BinaryHeap, HashSet},
+    collections::{HashMap, VecDeque},
     fmt::{Debug, Display},
+    iter::Peekable,
+    str::Chars,
     time::Instant,
 };
 
@@ -9,7 +10,91 @@ use ndarray::Array2;
 
 const PUZZLE: &str = include_str!("../../puzzles/day15.txt");
 
+/// `--elves HP,ATTACK` and `--goblins HP,ATTACK` fight one battle with other
+/// stats, and `--unit ROW,COL,HP,ATTACK` (as often as you like) gives the
+/// unit starting there stats of its own. For a map with more factions than
+/// that, `--free-for-all EGO` sets everyone against everyone, `--alliances
+/// EH,GO` puts factions on sides, and `--stats O,HP,ATTACK` sets any faction's
+/// stats.
+///
+/// `--log` prints everything that happens in part 1 as JSON lines, and
+/// `--replay FILE ROUND` draws the board from such a log after that round.
 fn main() {
+    const FLAGS: [&str; 7] = [
+        "--report",
+        "--elves",
+        "--goblins",
+        "--unit",
+        "--free-for-all",
+        "--alliances",
+        "--stats",
+    ];
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--log") {
+        let mut puzzle = Puzzle::new(PUZZLE).with_event_log();
+        puzzle.battle();
+        for event in puzzle.events.unwrap_or_default() {
+            println!("{}", event.to_json());
+        }
+        return;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--replay") {
+        let (Some(path), Some(round)) = (args.get(i + 1), args.get(i + 2)) else {
+            panic!("--replay needs a log file and a round");
+        };
+        let round: usize = round.parse().expect("round should be a number");
+        let events: Result<Vec<Event>, String> = std::fs::read_to_string(path)
+            .expect("could not read the log")
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(Event::from_json)
+            .collect();
+        match events.and_then(|events| replay(&events, round)) {
+            Ok(puzzle) => print!("After {} rounds:\n{puzzle:?}", puzzle.rounds),
+            Err(e) => println!("{e}"),
+        }
+        return;
+    }
+    if args.iter().any(|arg| FLAGS.contains(&arg.as_str())) {
+        let flag = |name: &str| {
+            args.iter()
+                .position(|arg| arg == name)
+                .map(|i| args[i + 1].as_str())
+        };
+        let mut config = match (flag("--free-for-all"), flag("--alliances")) {
+            (Some(symbols), _) => BattleConfig::free_for_all(symbols),
+            (None, Some(groups)) => BattleConfig::alliances(&groups.split(',').collect::<Vec<_>>()),
+            (None, None) => BattleConfig::default(),
+        };
+        let number = |n: &str| n.parse::<usize>().expect("expected a number");
+        let stats = |hp, attack| Stats {
+            hp: number(hp),
+            attack: number(attack),
+        };
+        for (flag, value) in args.iter().zip(args.iter().skip(1)) {
+            let fields: Vec<&str> = value.split(',').collect();
+            config = match (flag.as_str(), fields.as_slice()) {
+                ("--elves", &[hp, attack]) => config.with_stats('E', stats(hp, attack)),
+                ("--goblins", &[hp, attack]) => config.with_stats('G', stats(hp, attack)),
+                ("--stats", &[symbol, hp, attack]) if symbol.chars().count() == 1 => {
+                    config.with_stats(symbol.chars().next().unwrap(), stats(hp, attack))
+                }
+                ("--unit", &[row, col, hp, attack]) => {
+                    config.with_override((number(row), number(col)), stats(hp, attack))
+                }
+                ("--elves" | "--goblins" | "--stats" | "--unit", _) => {
+                    panic!("can't use {value} for {flag}")
+                }
+                _ => config,
+            };
+        }
+        let mut puzzle = Puzzle::with_config(PUZZLE, &config);
+        println!("{:?}", puzzle.battle());
+        println!("{puzzle:?}");
+        print!("{}", puzzle.report());
+        return;
+    }
+
     let start = Instant::now();
     println!("Part 1: {:?}", part1());
     println!("Part 2: {}", part2(PUZZLE).unwrap());
@@ -22,59 +107,453 @@ fn is_adjacent(a: &(usize, usize), b: &(usize, usize)) -> bool {
     dr + dc == 1
 }
 
+/// Who's left standing when nobody has anyone to fight, and the outcome:
+/// full rounds times the hit points they have left.
 #[derive(Debug, PartialEq)]
-enum GameResult {
-    ElvesWin(usize),
-    GoblinsWin(usize),
+struct GameResult {
+    /// The symbols of the factions that still have units, in order.
+    survivors: Vec<char>,
+    outcome: usize,
+}
+
+#[cfg(test)]
+impl GameResult {
+    fn won(survivors: &str, outcome: usize) -> Self {
+        Self {
+            survivors: survivors.chars().collect(),
+            outcome,
+        }
+    }
+}
+
+/// How much a unit can take and how hard it hits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    hp: usize,
+    attack: usize,
+}
+
+/// A side in the battle, drawn on the map with its own symbol.
+#[derive(Debug, Clone)]
+struct Faction {
+    symbol: char,
+    stats: Stats,
+}
+
+/// The factions and their stats, who fights whom, and any units that are
+/// special. The puzzle is elves against goblins, everyone starting on 200
+/// HP and hitting for 3, which is the default.
+#[derive(Debug, Clone)]
+struct BattleConfig {
+    factions: Vec<Faction>,
+    /// `hostile[a][b]` says whether faction `a` attacks faction `b`.
+    hostile: Vec<Vec<bool>>,
+    /// Units with stats of their own, by where they start.
+    overrides: HashMap<(usize, usize), Stats>,
+}
+
+impl Default for BattleConfig {
+    fn default() -> Self {
+        Self::alliances(&["E", "G"])
+    }
+}
+
+impl BattleConfig {
+    /// Every faction against every other.
+    fn free_for_all(symbols: &str) -> Self {
+        let groups: Vec<String> = symbols.chars().map(String::from).collect();
+        Self::alliances(&groups)
+    }
+
+    /// Factions in the same group fight on the same side; every group is
+    /// against every other.
+    fn alliances<S: AsRef<str>>(groups: &[S]) -> Self {
+        let membership: Vec<(char, usize)> = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, symbols)| symbols.as_ref().chars().map(move |c| (c, group)))
+            .collect();
+        let factions = membership
+            .iter()
+            .map(|&(symbol, _)| Faction {
+                symbol,
+                stats: Stats { hp: 200, attack: 3 },
+            })
+            .collect();
+        let hostile = membership
+            .iter()
+            .map(|(_, a)| membership.iter().map(|(_, b)| a != b).collect())
+            .collect();
+        Self {
+            factions,
+            hostile,
+            overrides: HashMap::new(),
+        }
+    }
+
+    fn faction(&self, symbol: char) -> Option<usize> {
+        self.factions.iter().position(|f| f.symbol == symbol)
+    }
+
+    fn with_stats(mut self, symbol: char, stats: Stats) -> Self {
+        let faction = self.faction(symbol).expect("no such faction");
+        self.factions[faction].stats = stats;
+        self
+    }
+
+    fn with_elf_attack(self, attack: usize) -> Self {
+        let elves = self.factions[self.faction('E').expect("no elves")].stats;
+        self.with_stats('E', Stats { attack, ..elves })
+    }
+
+    fn with_override(mut self, position: (usize, usize), stats: Stats) -> Self {
+        self.overrides.insert(position, stats);
+        self
+    }
 }
 
+/// What a unit got up to over the battle.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct History {
+    /// The ids of the units it finished off.
+    kills: Vec<usize>,
+    damage_dealt: usize,
+    damage_taken: usize,
+    /// Every square it stood on, starting with the one it started on.
+    path: Vec<(usize, usize)>,
+    /// The round it died in, if it did.
+    died: Option<usize>,
+}
+
+/// A unit keeps its id for the whole battle, dead or alive, so we can say
+/// afterwards who did what.
+#[derive(Debug, Clone)]
+struct Unit {
+    /// Numbered in reading order on the starting map, which is also where
+    /// it lives in `Puzzle::units`.
+    id: usize,
+    faction: usize,
+    hp: usize,
+    attack: usize,
+    position: (usize, usize),
+    history: History,
+}
+
+impl Unit {
+    fn alive(&self) -> bool {
+        self.hp > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 enum GameObject {
     Wall,
     Empty,
-    Goblin { hp: usize },
-    Elf { hp: usize },
+    /// The id of the unit standing here.
+    Unit(usize),
 }
 
-impl GameObject {
-    fn from(c: char) -> Self {
-        match c {
-            '#' => Self::Wall,
-            '.' => Self::Empty,
-            'G' => Self::Goblin { hp: 200 },
-            'E' => Self::Elf { hp: 200 },
-            _ => panic!("unexpected symbol"),
-        }
-    }
+/// Something that happened in a battle. Every log starts with the map and
+/// the units on it, so it can be replayed without the puzzle input. Rounds
+/// are counted from 1, and positions are `[row, col]` in JSON.
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Map {
+        map: String,
+    },
+    Unit {
+        unit: usize,
+        faction: char,
+        position: (usize, usize),
+        hp: usize,
+        attack: usize,
+    },
+    Moved {
+        round: usize,
+        unit: usize,
+        from: (usize, usize),
+        to: (usize, usize),
+    },
+    /// `hp` is what the target has left.
+    Attacked {
+        round: usize,
+        unit: usize,
+        target: usize,
+        damage: usize,
+        hp: usize,
+    },
+    Died {
+        round: usize,
+        unit: usize,
+        position: (usize, usize),
+    },
+    RoundEnded {
+        round: usize,
+    },
+    /// `unit` had nobody left to fight, so this round never finished.
+    CombatEnded {
+        round: usize,
+        unit: usize,
+    },
+}
 
-    fn attack(&mut self, power: usize) -> usize {
+impl Event {
+    /// One line of JSON.
+    fn to_json(&self) -> String {
+        let rc = |(row, col): &(usize, usize)| format!("[{row},{col}]");
         match self {
-            GameObject::Wall | GameObject::Empty => panic!(),
-            GameObject::Goblin { hp } | GameObject::Elf { hp } => {
-                *hp = hp.saturating_sub(power);
-                *hp
+            Event::Map { map } => format!(r#"{{"event":"map","map":"{}"}}"#, Json::escape(map)),
+            Event::Unit {
+                unit,
+                faction,
+                position,
+                hp,
+                attack,
+            } => format!(
+                r#"{{"event":"unit","unit":{unit},"faction":"{}","position":{},"hp":{hp},"attack":{attack}}}"#,
+                Json::escape(&faction.to_string()),
+                rc(position)
+            ),
+            Event::Moved {
+                round,
+                unit,
+                from,
+                to,
+            } => format!(
+                r#"{{"round":{round},"event":"moved","unit":{unit},"from":{},"to":{}}}"#,
+                rc(from),
+                rc(to)
+            ),
+            Event::Attacked {
+                round,
+                unit,
+                target,
+                damage,
+                hp,
+            } => format!(
+                r#"{{"round":{round},"event":"attacked","unit":{unit},"target":{target},"damage":{damage},"hp":{hp}}}"#
+            ),
+            Event::Died {
+                round,
+                unit,
+                position,
+            } => format!(
+                r#"{{"round":{round},"event":"died","unit":{unit},"position":{}}}"#,
+                rc(position)
+            ),
+            Event::RoundEnded { round } => {
+                format!(r#"{{"round":{round},"event":"round ended"}}"#)
+            }
+            Event::CombatEnded { round, unit } => {
+                format!(r#"{{"round":{round},"event":"combat ended","unit":{unit}}}"#)
             }
         }
     }
-}
 
-impl Display for GameObject {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// Read back one line of `to_json`.
+    fn from_json(line: &str) -> Result<Self, String> {
+        let fields = Json::parse_object(line)?;
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or(format!("no {name} in {line}"))
+        };
+        let number = |name: &str| match field(name)? {
+            Json::Number(n) => Ok(*n),
+            _ => Err(format!("{name} should be a number in {line}")),
+        };
+        let text = |name: &str| match field(name)? {
+            Json::String(s) => Ok(s.clone()),
+            _ => Err(format!("{name} should be a string in {line}")),
+        };
+        let position = |name: &str| match field(name)? {
+            Json::Array(a) => match a.as_slice() {
+                [Json::Number(row), Json::Number(col)] => Ok((*row, *col)),
+                _ => Err(format!("{name} should be [row, col] in {line}")),
+            },
+            _ => Err(format!("{name} should be [row, col] in {line}")),
+        };
+        Ok(match text("event")?.as_str() {
+            "map" => Event::Map { map: text("map")? },
+            "unit" => Event::Unit {
+                unit: number("unit")?,
+                faction: text("faction")?
+                    .chars()
+                    .next()
+                    .ok_or(format!("empty faction in {line}"))?,
+                position: position("position")?,
+                hp: number("hp")?,
+                attack: number("attack")?,
+            },
+            "moved" => Event::Moved {
+                round: number("round")?,
+                unit: number("unit")?,
+                from: position("from")?,
+                to: position("to")?,
+            },
+            "attacked" => Event::Attacked {
+                round: number("round")?,
+                unit: number("unit")?,
+                target: number("target")?,
+                damage: number("damage")?,
+                hp: number("hp")?,
+            },
+            "died" => Event::Died {
+                round: number("round")?,
+                unit: number("unit")?,
+                position: position("position")?,
+            },
+            "round ended" => Event::RoundEnded {
+                round: number("round")?,
+            },
+            "combat ended" => Event::CombatEnded {
+                round: number("round")?,
+                unit: number("unit")?,
+            },
+            other => return Err(format!("unknown event {other}")),
+        })
+    }
+
+    /// The round this happened in; the starting map counts as round 0.
+    fn round(&self) -> usize {
         match self {
-            GameObject::Wall => write!(f, "#"),
-            GameObject::Empty => write!(f, "."),
-            GameObject::Goblin { hp: _ } => write!(f, "G"),
-            GameObject::Elf { hp: _ } => write!(f, "E"),
+            Event::Map { .. } | Event::Unit { .. } => 0,
+            Event::Moved { round, .. }
+            | Event::Attacked { round, .. }
+            | Event::Died { round, .. }
+            | Event::RoundEnded { round }
+            | Event::CombatEnded { round, .. } => *round,
         }
     }
 }
 
-impl Debug for GameObject {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Wall => write!(f, "#"),
-            Self::Empty => write!(f, "."),
-            Self::Goblin { hp } => write!(f, "G({hp})"),
-            Self::Elf { hp } => write!(f, "E({hp})"),
+/// Just enough JSON to read our own event logs back: objects of numbers,
+/// strings, and arrays, with no nesting of objects.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Number(usize),
+    String(String),
+    Array(Vec<Json>),
+}
+
+impl Json {
+    /// The inside of a string literal, for anything `parse_value` can read
+    /// back. Map symbols can be anything at all, quotes, backslashes and
+    /// control characters included, and other JSON readers (`jq`, say) don't
+    /// accept control characters as they are.
+    fn escape(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '"' => escaped.push_str("\\\""),
+                '\n' => escaped.push_str("\\n"),
+                c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
+    fn parse_object(text: &str) -> Result<Vec<(String, Json)>, String> {
+        let mut chars = text.trim().chars().peekable();
+        let mut fields = vec![];
+        Self::expect(&mut chars, '{')?;
+        loop {
+            Self::skip_whitespace(&mut chars);
+            if chars.peek() == Some(&'}') && fields.is_empty() {
+                chars.next();
+                break;
+            }
+            let Json::String(key) = Self::parse_value(&mut chars)? else {
+                return Err(format!("expected a key in {text}"));
+            };
+            Self::expect(&mut chars, ':')?;
+            fields.push((key, Self::parse_value(&mut chars)?));
+            Self::skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => {}
+                Some('}') => break,
+                c => return Err(format!("expected , or }} but found {c:?} in {text}")),
+            }
+        }
+        match chars.next() {
+            None => Ok(fields),
+            Some(c) => Err(format!("unexpected {c:?} after the end of {text}")),
+        }
+    }
+
+    fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+        Self::skip_whitespace(chars);
+        match chars.next() {
+            Some('"') => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => return Ok(Json::String(s)),
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let c = Some(&hex)
+                                    .filter(|hex| hex.len() == 4)
+                                    .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+                                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                    .and_then(char::from_u32)
+                                    .ok_or(format!("bad escape \\u{hex}"))?;
+                                s.push(c);
+                            }
+                            Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                            c => return Err(format!("unsupported escape {c:?}")),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+            }
+            Some('[') => {
+                let mut values = vec![];
+                Self::skip_whitespace(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(Self::parse_value(chars)?);
+                    Self::skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Json::Array(values)),
+                        c => return Err(format!("expected , or ] but found {c:?}")),
+                    }
+                }
+            }
+            Some(c @ '0'..='9') => {
+                let mut n = c.to_digit(10).unwrap() as usize;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add(d as usize))
+                        .ok_or("number too big".to_string())?;
+                    chars.next();
+                }
+                Ok(Json::Number(n))
+            }
+            c => Err(format!("unexpected {c:?}")),
+        }
+    }
+
+    fn skip_whitespace(chars: &mut Peekable<Chars>) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+        Self::skip_whitespace(chars);
+        match chars.next() {
+            Some(c) if c == expected => Ok(()),
+            c => Err(format!("expected {expected} but found {c:?}")),
         }
     }
 }
@@ -83,34 +562,142 @@ struct Puzzle {
     objects: Array2<GameObject>,
     rows: usize,
     cols: usize,
+    /// Everyone who started the battle, by id.
+    units: Vec<Unit>,
+    /// What each faction looks like on the map.
+    symbols: Vec<char>,
+    hostile: Vec<Vec<bool>>,
+    /// How many full rounds have been fought.
+    rounds: usize,
+    /// Everything that has happened, if anyone asked.
+    events: Option<Vec<Event>>,
 }
 
 impl Puzzle {
     fn new(input: &str) -> Self {
+        Self::with_config(input, &BattleConfig::default())
+    }
+
+    /// The units get their stats here and keep them for the whole battle.
+    fn with_config(input: &str, config: &BattleConfig) -> Self {
         let rows = input.lines().count();
         let cols = input.lines().next().unwrap().len();
-        let data = input
-            .lines()
-            .flat_map(|line| line.chars().map(GameObject::from));
+        let mut objects = Array2::from_elem((rows, cols), GameObject::Wall);
+        let mut units = vec![];
+        for (row, line) in input.lines().enumerate() {
+            for (col, c) in line.chars().enumerate() {
+                let position = (row, col);
+                objects[position] = match c {
+                    '#' => GameObject::Wall,
+                    '.' => GameObject::Empty,
+                    _ => {
+                        let faction = config.faction(c).expect("unexpected symbol");
+                        let Stats { hp, attack } = config
+                            .overrides
+                            .get(&position)
+                            .copied()
+                            .unwrap_or(config.factions[faction].stats);
+                        let id = units.len();
+                        units.push(Unit {
+                            id,
+                            faction,
+                            hp,
+                            attack,
+                            position,
+                            history: History {
+                                path: vec![position],
+                                ..Default::default()
+                            },
+                        });
+                        GameObject::Unit(id)
+                    }
+                };
+            }
+        }
         Self {
-            objects: Array2::from_shape_vec((rows, cols), data.collect()).unwrap(),
+            objects,
             rows,
             cols,
+            units,
+            symbols: config.factions.iter().map(|f| f.symbol).collect(),
+            hostile: config.hostile.clone(),
+            rounds: 0,
+            events: None,
         }
     }
 
-    fn targets(&self, from: (usize, usize)) -> Vec<(usize, usize)> {
-        let mut t = Vec::new();
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                match (&self.objects[from], &self.objects[(i, j)]) {
-                    (GameObject::Goblin { hp: _ }, GameObject::Elf { hp: _ })
-                    | (GameObject::Elf { hp: _ }, GameObject::Goblin { hp: _ }) => t.push((i, j)),
-                    _ => {}
-                }
-            }
+    /// Start logging, with the map and the units as they are now.
+    fn with_event_log(mut self) -> Self {
+        let mut events = vec![Event::Map {
+            map: self.to_string(),
+        }];
+        for unit in self.living() {
+            events.push(Event::Unit {
+                unit: unit.id,
+                faction: self.symbols[unit.faction],
+                position: unit.position,
+                hp: unit.hp,
+                attack: unit.attack,
+            });
         }
-        t
+        self.events = Some(events);
+        self
+    }
+
+    fn log(&mut self, event: Event) {
+        if let Some(events) = self.events.as_mut() {
+            events.push(event);
+        }
+    }
+
+    /// The units still standing, in reading order.
+    fn living(&self) -> Vec<&Unit> {
+        let mut living: Vec<&Unit> = self.units.iter().filter(|unit| unit.alive()).collect();
+        living.sort_by_key(|unit| unit.position);
+        living
+    }
+
+    fn unit_at(&self, position: (usize, usize)) -> Option<&Unit> {
+        match self.objects.get(position) {
+            Some(GameObject::Unit(id)) => Some(&self.units[*id]),
+            _ => None,
+        }
+    }
+
+    fn id(&self, position: (usize, usize)) -> usize {
+        match self.unit_at(position) {
+            Some(unit) => unit.id,
+            None => panic!("no unit at {position:?}"),
+        }
+    }
+
+    /// The grid only says who's where; the unit itself keeps track of
+    /// where it's been.
+    fn move_unit(&mut self, id: usize, to: (usize, usize)) {
+        let unit = &mut self.units[id];
+        self.objects[unit.position] = GameObject::Empty;
+        self.objects[to] = GameObject::Unit(id);
+        unit.position = to;
+        unit.history.path.push(to);
+    }
+
+    fn symbol(&self, obj: &GameObject) -> char {
+        match obj {
+            GameObject::Wall => '#',
+            GameObject::Empty => '.',
+            GameObject::Unit(id) => self.symbols[self.units[*id].faction],
+        }
+    }
+
+    fn targets(&self, from: (usize, usize)) -> Vec<(usize, usize)> {
+        let Some(&Unit { faction: ours, .. }) = self.unit_at(from) else {
+            return vec![];
+        };
+        self.living()
+            .into_iter()
+            .filter(|unit| self.hostile[ours][unit.faction])
+            .map(|unit| unit.position)
+            .collect()
     }
 
     fn in_range(&self, targets: &[(usize, usize)]) -> Vec<(usize, usize)> {
@@ -128,127 +715,85 @@ impl Puzzle {
         r
     }
 
-    /// This function combines the reachable, nearest, and chosen steps.
-    /// We use a breadth-first search to discover reachable empty tiles,
-    /// which are adjacent to our list of targets. This is a fallible
-    /// operation: there may be no path to any of those empty tiles.
-    ///
-    /// This greedy algorithm makes good use of tuple ordering to terminate
-    /// upon reaching a solution. The heap is ordered first by distance,
-    /// then by reading order, which is exactly what we need.
-    fn reachable_nearest_choose(
-        &self,
-        from: (usize, usize),
-        ranges: &[(usize, usize)],
-    ) -> Option<(usize, usize)> {
-        let mut discovered = HashSet::new();
-        let mut frontier = BinaryHeap::new();
-
-        frontier.push(Reverse((0, from)));
-        discovered.insert(from);
-
-        while let Some(Reverse((distance, position))) = frontier.pop() {
-            if ranges.contains(&position) {
-                return Some(position);
-            }
+    /// The four squares next to this one, in reading order. Off the edge
+    /// wraps around to a huge index, which `get` turns down.
+    fn neighbours(&self, (row, col): (usize, usize)) -> [(usize, usize); 4] {
+        [(-1, 0), (0, -1), (0, 1), (1, 0)]
+            .map(|(dr, dc)| (row.wrapping_add_signed(dr), col.wrapping_add_signed(dc)))
+    }
 
-            for (dr, dc) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
-                let candidate = (
-                    position.0.wrapping_add_signed(dr),
-                    position.1.wrapping_add_signed(dc),
-                );
+    /// How many steps it takes to get from here to every empty square we can
+    /// reach, through other empty squares. One breadth-first search answers
+    /// every "how far" question for this turn, where I used to run a fresh
+    /// search for each one.
+    fn distances(&self, from: (usize, usize)) -> Array2<Option<usize>> {
+        let mut distances = Array2::from_elem((self.rows, self.cols), None);
+        distances[from] = Some(0);
+        let mut frontier = VecDeque::from([from]);
+        while let Some(position) = frontier.pop_front() {
+            let distance = distances[position].unwrap();
+            for candidate in self.neighbours(position) {
                 if let Some(GameObject::Empty) = self.objects.get(candidate)
-                    && !discovered.contains(&candidate)
+                    && distances[candidate].is_none()
                 {
-                    discovered.insert(candidate);
-                    frontier.push(Reverse((distance + 1, candidate)));
+                    distances[candidate] = Some(distance + 1);
+                    frontier.push_back(candidate);
                 }
             }
         }
+        distances
+    }
 
-        None
+    /// This function combines the reachable, nearest, and chosen steps.
+    /// This is a fallible operation: there may be no path to any of the
+    /// squares in range.
+    ///
+    /// Tuple ordering does the tie-breaking for us: nearest first, then
+    /// reading order, which is exactly what we need.
+    fn reachable_nearest_choose(
+        &self,
+        from: (usize, usize),
+        ranges: &[(usize, usize)],
+    ) -> Option<(usize, usize)> {
+        let distances = self.distances(from);
+        ranges
+            .iter()
+            .filter_map(|&position| distances[position].map(|distance| (distance, position)))
+            .min()
+            .map(|(_, position)| position)
     }
 
     fn next_step(&self, from: (usize, usize), to: (usize, usize)) -> (usize, usize) {
         // Same as choosing the nearest among many *reachable* targets,
         // selecting the next step towards our chosen target is an
         // infallible operation. Don't call this on something where there
-        // is no path.
-        let mut neighbors: Vec<(usize, (usize, usize))> = [(-1, 0), (0, -1), (0, 1), (1, 0)]
+        // is no path. Searching backwards from the target tells us how far
+        // each of our neighbours is from it, all at once.
+        let distances = self.distances(to);
+        self.neighbours(from)
             .into_iter()
-            .filter_map(|(dr, dc)| {
-                let candidate = (
-                    from.0.wrapping_add_signed(dr),
-                    from.1.wrapping_add_signed(dc),
-                );
-
-                if let Some(obj) = self.objects.get(candidate)
-                    && !matches!(obj, GameObject::Empty)
-                {
-                    return None;
-                }
-
-                let distance = self.bfs(candidate, to);
-                if let Some(d) = distance {
-                    Some((d, candidate))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let min_dist = neighbors.iter().min_by(|x, y| x.0.cmp(&y.0)).unwrap().0;
-        neighbors.retain(|(d, _position)| *d == min_dist);
-        neighbors[0].1
-    }
-
-    fn bfs(&self, from: (usize, usize), to: (usize, usize)) -> Option<usize> {
-        let mut discovered = HashSet::new();
-        let mut frontier = BinaryHeap::new();
-        frontier.push(Reverse((0, from)));
-        discovered.insert(from);
-
-        while let Some(Reverse((distance, position))) = frontier.pop() {
-            if position == to {
-                return Some(distance);
-            }
-
-            let (row, col) = position;
-            for (dr, dc) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
-                let candidate = (row.wrapping_add_signed(dr), col.wrapping_add_signed(dc));
-                if !discovered.contains(&candidate)
-                    && matches!(self.objects.get(candidate), Some(GameObject::Empty))
-                {
-                    discovered.insert(candidate);
-                    frontier.push(Reverse((distance + 1, candidate)));
-                }
-            }
-        }
-
-        None
+            .filter(|&candidate| matches!(self.objects.get(candidate), Some(GameObject::Empty)))
+            .filter_map(|candidate| distances[candidate].map(|distance| (distance, candidate)))
+            .min()
+            .unwrap()
+            .1
     }
 
-    fn round(&mut self, elf_attack_power: usize) -> bool {
-        let mut units = Vec::new();
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                if let Some(GameObject::Elf { hp: _ } | GameObject::Goblin { hp: _ }) =
-                    self.objects.get((i, j))
-                {
-                    units.push((i, j));
-                }
-            }
-        }
+    fn round(&mut self) -> bool {
+        let round = self.rounds + 1;
+        let order: Vec<usize> = self.living().iter().map(|unit| unit.id).collect();
 
-        'turn: for mut from in units {
-            // Killed units get replaced by empty tiles.
-            if let Some(GameObject::Empty) = self.objects.get(from) {
+        'turn: for id in order {
+            // Units killed earlier in the round don't get a turn.
+            if !self.units[id].alive() {
                 continue 'turn;
             }
+            let mut from = self.units[id].position;
 
             // First the unit moves.
             let t = self.targets(from);
             if t.is_empty() {
-                // println!("{from:?} has nothing to attack");
+                self.log(Event::CombatEnded { round, unit: id });
                 return false;
             }
 
@@ -260,86 +805,121 @@ impl Puzzle {
                     self.reachable_nearest_choose(from, &enemies_in_range)
                 {
                     let to = self.next_step(from, chosen_destination);
-                    // "move" the game object by swapping.
-                    self.objects.swap(from, to);
+                    self.move_unit(id, to);
+                    self.log(Event::Moved {
+                        round,
+                        unit: id,
+                        from,
+                        to,
+                    });
                     from = to;
                 } else {
                     continue 'turn;
                 }
             }
 
-            // Then the unit attacks.
-            let adj: Vec<_> = t.iter().filter(|t| is_adjacent(&from, t)).collect();
-            if adj.is_empty() {
+            // Then the unit attacks the adjacent target with the fewest HP.
+            let Some((hp, target)) = t
+                .iter()
+                .filter(|t| is_adjacent(&from, t))
+                .map(|&t| {
+                    let target = self.id(t);
+                    (self.units[target].hp, target)
+                })
+                .min_by_key(|&(hp, target)| (hp, self.units[target].position))
+            else {
                 // Nothing adjacent to attack.
                 continue 'turn;
-            }
-            let (_min_hp, to) = adj
-                .into_iter()
-                .map(|&t| match self.objects[t] {
-                    GameObject::Empty | GameObject::Wall => panic!(),
-                    GameObject::Goblin { hp } | GameObject::Elf { hp } => (hp, t),
-                })
-                .min()
-                .unwrap();
-            // The adjacent target with the fewest HP.
-            // This function returns true if the object's HP went to zero.
-            let attack_power = match self.objects[from] {
-                GameObject::Wall | GameObject::Empty => panic!(),
-                GameObject::Goblin { hp: _ } => 3,
-                GameObject::Elf { hp: _ } => elf_attack_power,
             };
-            let enemy_health = self.objects[to].attack(attack_power);
-            if enemy_health == 0 {
-                self.objects[to] = GameObject::Empty;
+            let damage = self.units[id].attack.min(hp);
+            self.units[target].hp -= damage;
+            self.units[target].history.damage_taken += damage;
+            self.units[id].history.damage_dealt += damage;
+            self.log(Event::Attacked {
+                round,
+                unit: id,
+                target,
+                damage,
+                hp: self.units[target].hp,
+            });
+            if !self.units[target].alive() {
+                let position = self.units[target].position;
+                self.objects[position] = GameObject::Empty;
+                self.units[target].history.died = Some(round);
+                self.units[id].history.kills.push(target);
+                self.log(Event::Died {
+                    round,
+                    unit: target,
+                    position,
+                });
             }
         }
+        self.rounds = round;
+        self.log(Event::RoundEnded { round });
         true
     }
 
-    fn battle(&mut self, elf_attack_power: usize) -> GameResult {
-        let mut i = 0;
-        loop {
-            if self.round(elf_attack_power) {
-                i += 1;
-            } else {
-                break;
-            }
+    fn battle(&mut self) -> GameResult {
+        while self.round() {}
+        let living = self.living();
+        let hp: usize = living.iter().map(|unit| unit.hp).sum();
+        let mut survivors: Vec<usize> = living.iter().map(|unit| unit.faction).collect();
+        survivors.sort_unstable();
+        survivors.dedup();
+        assert!(!survivors.is_empty());
+        for &a in survivors.iter() {
+            assert!(survivors.iter().all(|&b| !self.hostile[a][b]));
         }
-        let mut elves = 0usize;
-        let mut goblins = 0usize;
-        let hp = self.objects.fold(0, |acc, obj| {
-            acc + match obj {
-                GameObject::Empty | GameObject::Wall => 0,
-                GameObject::Elf { hp } => {
-                    elves += 1;
-                    *hp
-                }
-                GameObject::Goblin { hp } => {
-                    goblins += 1;
-                    *hp
-                }
-            }
-        });
-        let outcome = i * hp;
-        assert!(elves == 0 || goblins == 0);
-        assert!(!(elves == 0 && goblins == 0));
-        assert_eq!(elves, self.elf_count());
-        if elves > 0 {
-            GameResult::ElvesWin(outcome)
-        } else {
-            GameResult::GoblinsWin(outcome)
+        GameResult {
+            survivors: survivors.into_iter().map(|f| self.symbols[f]).collect(),
+            outcome: self.rounds * hp,
         }
     }
 
-    fn elf_count(&self) -> usize {
-        self.objects.iter().fold(0, |acc, obj| {
-            if matches!(obj, GameObject::Elf { hp: _ }) {
-                acc + 1
-            } else {
-                acc
-            }
-        })
+    /// How many units of this faction are left.
+    fn count(&self, symbol: char) -> usize {
+        self.living()
+            .iter()
+            .filter(|unit| self.symbols[unit.faction] == symbol)
+            .count()
+    }
+
+    fn report(&self) -> Report<'_> {
+        Report(self)
+    }
+}
+
+/// Everyone's history, one line per unit, for the end of a battle.
+struct Report<'a>(&'a Puzzle);
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Report(puzzle) = self;
+        writeln!(f, "unit side  hp dealt taken steps kills")?;
+        for unit in puzzle.units.iter() {
+            let History {
+                kills,
+                damage_dealt,
+                damage_taken,
+                path,
+                died,
+            } = &unit.history;
+            let kills: Vec<String> = kills.iter().map(usize::to_string).collect();
+            let fate = match died {
+                Some(round) => format!("died in round {round}"),
+                None => format!("alive at {:?}", unit.position),
+            };
+            writeln!(
+                f,
+                "{:>4} {:>4} {:>3} {damage_dealt:>5} {damage_taken:>5} {:>5} {:<5} {fate}",
+                unit.id,
+                puzzle.symbols[unit.faction],
+                unit.hp,
+                path.len() - 1,
+                kills.join(",")
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -347,7 +927,7 @@ impl Display for Puzzle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for row in self.objects.rows() {
             for obj in row {
-                write!(f, "{obj}")?;
+                write!(f, "{}", self.symbol(obj))?;
             }
             writeln!(f)?;
         }
@@ -359,20 +939,19 @@ impl Debug for Puzzle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for row in self.objects.rows() {
             for obj in row {
-                write!(f, "{obj}")?;
+                write!(f, "{}", self.symbol(obj))?;
             }
             write!(f, "   ")?;
             write!(
                 f,
                 "{}",
                 row.iter()
-                    .filter(|obj| {
-                        matches!(
-                            obj,
-                            GameObject::Elf { hp: _ } | GameObject::Goblin { hp: _ }
-                        )
+                    .filter_map(|obj| match obj {
+                        GameObject::Unit(id) => {
+                            Some(format!("{}({})", self.symbol(obj), self.units[*id].hp))
+                        }
+                        _ => None,
                     })
-                    .map(|obj| format!("{obj:?}"))
                     .collect::<Vec<_>>()
                     .join(", ")
             )?;
@@ -382,11 +961,66 @@ impl Debug for Puzzle {
     }
 }
 
+/// Rebuild the board as it was after `round` full rounds (or as far as the
+/// log goes, if it ends sooner) from nothing but the log. We don't know who
+/// was fighting whom, but we don't need to, just to draw it.
+fn replay(events: &[Event], round: usize) -> Result<Puzzle, String> {
+    let Some(Event::Map { map }) = events.first() else {
+        return Err("the log should start with the map".to_string());
+    };
+    let mut symbols = String::new();
+    for c in map.chars() {
+        if !"#.\n".contains(c) && !symbols.contains(c) {
+            symbols.push(c);
+        }
+    }
+    let config = BattleConfig::free_for_all(&symbols);
+    let mut puzzle = Puzzle::with_config(map, &config);
+
+    for event in events.iter().take_while(|event| event.round() <= round) {
+        let unit_at = |puzzle: &Puzzle, position, unit| match puzzle.unit_at(position) {
+            Some(found) if found.id == unit => Ok(()),
+            _ => Err(format!("unit {unit} isn't at {position:?} for {event:?}")),
+        };
+        match *event {
+            Event::Map { .. } | Event::CombatEnded { .. } => {}
+            Event::Unit {
+                unit,
+                faction,
+                position,
+                hp,
+                attack,
+            } => {
+                unit_at(&puzzle, position, unit)?;
+                let unit = &mut puzzle.units[unit];
+                unit.faction = config.faction(faction).unwrap();
+                unit.hp = hp;
+                unit.attack = attack;
+            }
+            Event::Moved { unit, from, to, .. } => {
+                unit_at(&puzzle, from, unit)?;
+                puzzle.move_unit(unit, to);
+            }
+            Event::Attacked {
+                target, hp: left, ..
+            } => match puzzle.units.get_mut(target) {
+                Some(unit) if unit.alive() => unit.hp = left,
+                _ => return Err(format!("unit {target} isn't on the board for {event:?}")),
+            },
+            Event::Died { unit, position, .. } => {
+                unit_at(&puzzle, position, unit)?;
+                puzzle.objects[position] = GameObject::Empty;
+                puzzle.units[unit].hp = 0;
+            }
+            Event::RoundEnded { round } => puzzle.rounds = round,
+        }
+    }
+    Ok(puzzle)
+}
+
 fn part1() -> usize {
     let mut solver = Puzzle::new(PUZZLE);
-    match solver.battle(3) {
-        GameResult::ElvesWin(outcome) | GameResult::GoblinsWin(outcome) => outcome,
-    }
+    solver.battle().outcome
 }
 
 /// Somehow not the prettiest bisection that I ever wrote, but it works and
@@ -402,17 +1036,14 @@ fn part2(input: &str) -> Option<usize> {
         }
         let m = (low + high) / 2;
 
-        let mut solver = Puzzle::new(input);
-        let outcome = match solver.battle(m) {
-            GameResult::ElvesWin(outcome) if solver.elf_count() == elf_count => {
-                high = m;
-                outcome
-            }
-            GameResult::ElvesWin(outcome) | GameResult::GoblinsWin(outcome) => {
-                low = m;
-                outcome
-            }
-        };
+        let config = BattleConfig::default().with_elf_attack(m);
+        let mut solver = Puzzle::with_config(input, &config);
+        let outcome = solver.battle().outcome;
+        if solver.count('E') == elf_count {
+            high = m;
+        } else {
+            low = m;
+        }
 
         if low == high {
             return Some(outcome);
@@ -530,7 +1161,7 @@ mod beverage_bandits {
     fn larger_movement() {
         let mut p = Puzzle::new(MOVEMENT);
         for _ in 1..5 {
-            p.round(3);
+            p.round();
         }
         assert_eq!(
             p.to_string(),
@@ -560,7 +1191,7 @@ mod beverage_bandits {
                 println!("After {i} rounds:");
             }
             println!("{p:?}");
-            p.round(3);
+            p.round();
             println!();
         }
     }
@@ -568,52 +1199,213 @@ mod beverage_bandits {
     #[test]
     fn losing_batle_outcome() {
         let mut p = Puzzle::new(BATTLE);
-        assert_eq!(p.battle(3), GameResult::GoblinsWin(27730))
+        assert_eq!(p.battle(), GameResult::won("G", 27730))
+    }
+
+    #[test]
+    fn configured_stats() {
+        let config = BattleConfig::default().with_elf_attack(15);
+        let mut p = Puzzle::with_config(BATTLE, &config);
+        assert_eq!(p.battle(), GameResult::won("E", 4988));
+
+        // The goblin gets in three hits before the elf's fourth turn, and
+        // kills it on its own fourth.
+        let config = BattleConfig::default().with_override((1, 2), Stats { hp: 10, attack: 3 });
+        let mut p = Puzzle::with_config("####\n#GE#\n####", &config);
+        assert_eq!(p.unit_at((1, 2)).map(|unit| unit.hp), Some(10));
+        assert_eq!(p.battle(), GameResult::won("G", 4 * 191));
+    }
+
+    #[test]
+    fn factions() {
+        // The elves and the orcs gang up on the goblin.
+        let config = BattleConfig::alliances(&["EO", "G"]);
+        let mut p = Puzzle::with_config("#####\n#EGO#\n#####", &config);
+        assert_eq!(p.battle(), GameResult::won("EO", 33 * (101 + 200)));
+
+        // Now the goblin goes down just the same, but then the orc walks over
+        // and finishes off the elf.
+        let config = BattleConfig::free_for_all("EGO");
+        let mut p = Puzzle::with_config("#####\n#EGO#\n#####", &config);
+        assert_eq!(p.battle(), GameResult::won("O", 67 * 101));
+        assert_eq!(p.to_string(), "#####\n#.O.#\n#####\n");
+
+        // Two factions against each other is the usual game.
+        let config = BattleConfig::free_for_all("EG");
+        let mut p = Puzzle::with_config(BATTLE, &config);
+        assert_eq!(p.battle(), GameResult::won("G", 27730));
+    }
+
+    #[test]
+    fn unit_histories() {
+        let config = BattleConfig::free_for_all("EGO");
+        let mut p = Puzzle::with_config("#####\n#EGO#\n#####", &config);
+        p.battle();
+        let [elf, goblin, orc] = [&p.units[0], &p.units[1], &p.units[2]];
+        assert_eq!(goblin.history.died, Some(34));
+        assert_eq!(elf.history.died, Some(67));
+        // The elf goes first, so it gets the goblin before the orc gets it.
+        assert_eq!(elf.history.kills, vec![1]);
+        assert_eq!(orc.history.kills, vec![0]);
+        assert_eq!(orc.history.path, vec![(1, 3), (1, 2)]);
+        assert_eq!(orc.history.damage_taken, 99);
+
+        // Every point of damage dealt was taken by someone, and every dead
+        // unit was killed by exactly one other.
+        let mut p = Puzzle::new(BATTLE);
+        p.battle();
+        let dealt: usize = p.units.iter().map(|u| u.history.damage_dealt).sum();
+        let taken: usize = p.units.iter().map(|u| u.history.damage_taken).sum();
+        assert_eq!(dealt, taken);
+        let mut killed: Vec<usize> = p
+            .units
+            .iter()
+            .flat_map(|u| u.history.kills.clone())
+            .collect();
+        killed.sort_unstable();
+        let dead: Vec<usize> = p
+            .units
+            .iter()
+            .filter(|u| !u.alive())
+            .map(|u| u.id)
+            .collect();
+        assert_eq!(killed, dead);
+        for unit in p.units.iter() {
+            assert_eq!(unit.history.path.last(), Some(&unit.position));
+            assert!(
+                unit.history
+                    .path
+                    .windows(2)
+                    .all(|w| is_adjacent(&w[0], &w[1]))
+            );
+        }
+        assert_eq!(
+            p.report().to_string().lines().nth(2),
+            Some("   1    E   0    69   200     0       died in round 23")
+        );
+    }
+
+    #[test]
+    fn event_log() {
+        let mut p = Puzzle::new(BATTLE).with_event_log();
+        p.battle();
+        let events = p.events.unwrap();
+        assert_eq!(
+            events[7].to_json(),
+            r#"{"round":1,"event":"moved","unit":0,"from":[1,2],"to":[1,3]}"#
+        );
+        assert_eq!(
+            events.last(),
+            Some(&Event::CombatEnded { round: 48, unit: 0 })
+        );
+        for event in events.iter() {
+            assert_eq!(Event::from_json(&event.to_json()).as_ref(), Ok(event));
+        }
+
+        // Every round of the replay looks just like the real thing.
+        let mut p = Puzzle::new(BATTLE);
+        for round in 0..=48 {
+            let replayed = replay(&events, round).unwrap();
+            assert_eq!(format!("{replayed:?}"), format!("{p:?}"), "round {round}");
+            p.round();
+        }
+    }
+
+    /// Faction symbols go in strings too, so they need escaping like maps.
+    #[test]
+    fn awkward_symbols() {
+        for faction in ['"', '\\', '\n', '\t', '\0', 'E'] {
+            let event = Event::Unit {
+                unit: 3,
+                faction,
+                position: (1, 2),
+                hp: 200,
+                attack: 3,
+            };
+            let json = event.to_json();
+            assert!(json.chars().all(|c| !c.is_control()), "{json}");
+            assert_eq!(Event::from_json(&json), Ok(event));
+        }
+        assert_eq!(
+            Event::from_json(r##"{"event":"map","map":"#\u0009\u00e9"}"##),
+            Ok(Event::Map {
+                map: "#\t\u{e9}".to_string()
+            })
+        );
+        assert!(Event::from_json(r#"{"event":"map","map":"\u00"}"#).is_err());
+        assert!(Event::from_json(r#"{"event":"map","map":"\ud800"}"#).is_err());
+        assert_eq!(
+            Event::from_json(r#"{"round":18446744073709551615,"event":"round ended"}"#),
+            Ok(Event::RoundEnded { round: usize::MAX })
+        );
+    }
+
+    #[test]
+    fn bad_logs() {
+        assert!(Event::from_json(r#"{"event":"moved","unit":1}"#).is_err());
+        assert!(Event::from_json(r#"{"event":"exploded"}"#).is_err());
+        assert!(Event::from_json(r##"{"event":"map","map":"#"##).is_err());
+        assert!(
+            Event::from_json(r#"{"round":18446744073709551616,"event":"round ended"}"#).is_err()
+        );
+        assert!(replay(&[Event::RoundEnded { round: 1 }], 1).is_err());
+        let events = [
+            Event::Map {
+                map: "#####\n#EG.#\n#####\n".to_string(),
+            },
+            Event::Moved {
+                round: 1,
+                unit: 1,
+                from: (1, 1),
+                to: (1, 2),
+            },
+        ];
+        assert!(replay(&events, 1).is_err());
     }
 
     #[test]
     fn battle_36334() {
         let mut p = Puzzle::new(BATTLE_36334);
         println!("{p:?}");
-        let outcome = p.battle(3);
+        let outcome = p.battle();
         println!("{p:?}");
-        assert_eq!(outcome, GameResult::ElvesWin(36334))
+        assert_eq!(outcome, GameResult::won("E", 36334))
     }
 
     #[test]
     fn battle_39514() {
         let mut p = Puzzle::new(BATTLE_39514);
         println!("{p:?}");
-        let outcome = p.battle(3);
+        let outcome = p.battle();
         println!("{p:?}");
-        assert_eq!(outcome, GameResult::ElvesWin(39514))
+        assert_eq!(outcome, GameResult::won("E", 39514))
     }
 
     #[test]
     fn battle_27755() {
         let mut p = Puzzle::new(BATTLE_27755);
         println!("{p:?}");
-        let outcome = p.battle(3);
+        let outcome = p.battle();
         println!("{p:?}");
-        assert_eq!(outcome, GameResult::GoblinsWin(27755))
+        assert_eq!(outcome, GameResult::won("G", 27755))
     }
 
     #[test]
     fn battle_28944() {
         let mut p = Puzzle::new(BATTLE_28944);
         println!("{p:?}");
-        let outcome = p.battle(3);
+        let outcome = p.battle();
         println!("{p:?}");
-        assert_eq!(outcome, GameResult::GoblinsWin(28944))
+        assert_eq!(outcome, GameResult::won("G", 28944))
     }
 
     #[test]
     fn battle_18740() {
         let mut p = Puzzle::new(BATTLE_18740);
         println!("{p:?}");
-        let outcome = p.battle(3);
+        let outcome = p.battle();
         println!("{p:?}");
-        assert_eq!(outcome, GameResult::GoblinsWin(18740))
+        assert_eq!(outcome, GameResult::won("G", 18740))
     }
 
     #[test]
@@ -640,4 +1432,65 @@ mod beverage_bandits {
     fn part2_1140() {
         assert_eq!(part2(BATTLE_1140), Some(1140))
     }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn bench_samples() {
+        let samples = [
+            BATTLE,
+            BATTLE_36334,
+            BATTLE_39514,
+            BATTLE_27755,
+            BATTLE_28944,
+            BATTLE_18740,
+        ];
+        let start = Instant::now();
+        for _ in 0..20 {
+            for sample in samples {
+                Puzzle::new(sample).battle();
+            }
+        }
+        println!("Battles: {:?}", start.elapsed() / 20);
+        let start = Instant::now();
+        for _ in 0..20 {
+            for sample in [
+                BATTLE_4988,
+                BATTLE_31284,
+                BATTLE_3478,
+                BATTLE_6474,
+                BATTLE_1140,
+            ] {
+                part2(sample);
+            }
+        }
+        println!("Part 2: {:?}", start.elapsed() / 20);
+
+        let map = arena(32);
+        let start = Instant::now();
+        let result = Puzzle::new(&map).battle();
+        println!("32x32 arena: {:?} ({result:?})", start.elapsed());
+    }
+
+    /// A square room about the size of a real puzzle input, with pillars
+    /// dotted around and two armies starting at opposite ends.
+    fn arena(size: usize) -> String {
+        let mut map = String::new();
+        for row in 0..size {
+            for col in 0..size {
+                let edge = row == 0 || col == 0 || row == size - 1 || col == size - 1;
+                let c = if edge || (row % 4 == 2 && col % 5 == 2) {
+                    '#'
+                } else if row < 5 && (row + col) % 3 == 0 {
+                    'G'
+                } else if row >= size - 5 && (row + col) % 3 == 0 {
+                    'E'
+                } else {
+                    '.'
+                };
+                map.push(c);
+            }
+            map.push('\n');
+        }
+        map
+    }
 }